@@ -1,5 +1,5 @@
+use crate::Error;
 use hyper::{Method, StatusCode};
-use tokio::runtime::Builder;
 use tokio::sync::mpsc::Sender;
 
 use bytes::Bytes;
@@ -7,16 +7,307 @@ use http_body_util::Full;
 use hyper::service::Service;
 use hyper::{body::Incoming as IncomingBody, Request, Response};
 
+use crate::esi::Roster;
+use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, PoisonError};
 
-static CONFIRM: &[u8] = b"<html><head><title>Telescope login</title><style>body{font-family: monospace;background-color: gray;color: whitesmoke;}</style></head><body><h1>Telescope</h1><p>Logged in!, now you can close this window safely.</p></body></html>";
+static SUCCESS_TEMPLATE: &str = include_str!("../assets/server.html");
+static FAILURE_TEMPLATE: &str = include_str!("../assets/server_error.html");
 static NOT_VALID: &[u8] = b"Invalid Request";
+static MISSING_PARAMETERS: &str = "the SSO answer is missing the code or the state";
+static INVALID_STATE: &str = "this login request is not valid or was already used";
+
+/// What the callback server hands to the caller waiting for the login: the
+/// `(code, state)` pair, or the error the SSO redirected with.
+pub type CallbackResult = Result<(String, String), Error>;
+
+/// Parameters the SSO redirects to the callback URL with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallbackParams {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    /// Set instead of `code` when the login failed, like when the user denies consent.
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
+
+impl CallbackParams {
+    /// Parses the query string of the callback, percent-decoding the values.
+    /// A repeated parameter makes the whole callback invalid.
+    pub fn parse(query: &str) -> Result<Self, Error> {
+        let mut params = CallbackParams::default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            let field = match key.as_ref() {
+                "code" => &mut params.code,
+                "state" => &mut params.state,
+                "error" => &mut params.error,
+                "error_description" => &mut params.error_description,
+                _ => continue,
+            };
+            if field.is_some() {
+                return Err(Error::InvalidCallback(format!("repeated parameter {}", key)));
+            }
+            *field = Some(value.into_owned()).filter(|value| !value.is_empty());
+        }
+        Ok(params)
+    }
+
+    /// Parses what the user pasted after logging in without the callback server:
+    /// the whole redirect URL or just its query string.
+    pub fn from_redirect(redirect: &str) -> Result<Self, Error> {
+        let redirect = redirect.trim();
+        let query = redirect.split_once('?').map_or(redirect, |(_, query)| query);
+        let query = query.split_once('#').map_or(query, |(query, _)| query);
+        CallbackParams::parse(query)
+    }
+
+    /// Parses the custom scheme URL the operating system opened the application
+    /// with, which must be the registered `callback` plus the query.
+    #[cfg(feature = "native-auth-flow")]
+    pub fn from_deep_link(url: &str, callback: &str) -> Result<Self, Error> {
+        let url = url.trim();
+        let target = url.split(['?', '#']).next().unwrap_or_default();
+        let expected = callback.split(['?', '#']).next().unwrap_or_default();
+        // the scheme is case insensitive, the rest must match as registered
+        let same = match (target.split_once("://"), expected.split_once("://")) {
+            (Some((scheme, rest)), Some((expected_scheme, expected_rest))) => {
+                scheme.eq_ignore_ascii_case(expected_scheme)
+                    && rest.trim_end_matches('/') == expected_rest.trim_end_matches('/')
+            }
+            _ => false,
+        };
+        if !same {
+            return Err(Error::InvalidCallback(format!("{} is not the login callback", target)));
+        }
+        CallbackParams::from_redirect(url)
+    }
+
+    /// Validates the callback against the pending logins the way the callback
+    /// server does: only a pending login may complete or cancel, and an SSO error
+    /// ends it. An unknown state is [`Error::AuthStateReplayed`].
+    pub fn check(self, states: Option<&PendingStates>) -> CallbackResult {
+        if let Some(states) = states {
+            let known = self.state.as_deref().is_some_and(|state| states.contains(state));
+            if !known && (self.state.is_some() || self.error.is_some()) {
+                return Err(Error::AuthStateReplayed);
+            }
+        }
+        let state = self.state.clone();
+        let result = self.into_result();
+        if let (Err(Error::Sso { .. }), Some(states), Some(state)) = (&result, states, &state) {
+            // the login is over, its state can't be used anymore
+            states.cancel(state);
+        }
+        result
+    }
+
+    /// The `(code, state)` pair, or the SSO error as [`Error::Sso`].
+    pub fn into_result(self) -> CallbackResult {
+        if let Some(error) = self.error {
+            return Err(Error::Sso {
+                error,
+                description: self.error_description,
+            });
+        }
+        match (self.code, self.state) {
+            (Some(code), Some(state)) => Ok((code, state)),
+            _ => Err(Error::InvalidCallback(MISSING_PARAMETERS.to_string())),
+        }
+    }
+}
+
+/// Pages the callback server answers the browser with once the SSO redirects to it.
+///
+/// `{{app_name}}` is replaced in both templates, `{{character}}` in the success one
+/// and `{{reason}}` in the failure one.
+#[derive(Debug, Clone)]
+pub struct CallbackPages {
+    pub success: String,
+    pub failure: String,
+    pub app_name: String,
+}
+
+impl Default for CallbackPages {
+    fn default() -> Self {
+        CallbackPages {
+            success: SUCCESS_TEMPLATE.to_string(),
+            failure: FAILURE_TEMPLATE.to_string(),
+            app_name: String::from("Telescope"),
+        }
+    }
+}
+
+impl CallbackPages {
+    /// `character` is the name of the character logging in, when it is known already.
+    pub fn render_success(&self, character: Option<&str>) -> String {
+        self.success
+            .replace("{{app_name}}", &escape_html(&self.app_name))
+            .replace("{{character}}", &escape_html(character.unwrap_or("Your character")))
+    }
+
+    pub fn render_failure(&self, reason: &str) -> String {
+        self.failure
+            .replace("{{app_name}}", &escape_html(&self.app_name))
+            .replace("{{reason}}", &escape_html(reason))
+    }
+}
+
+// values come from the query string, they must not inject markup in the page
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `state` values of the logins started and not completed yet,
+/// shared between the callback server and the [`crate::esi::EsiManager`].
+#[derive(Debug, Clone, Default)]
+pub struct PendingStates {
+    // logins upgrading a character keep its id, only that character may complete them
+    states: Arc<Mutex<HashMap<String, Option<i32>>>>,
+    // logins an SSO error ended, until the manager drops their stored sessions
+    cancelled: Arc<Mutex<Vec<String>>>,
+}
+
+impl PendingStates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, state: &str) {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.insert(state.to_string(), None);
+    }
+
+    /// Registers a login that only `character_id` may complete.
+    pub fn insert_for(&self, state: &str, character_id: i32) {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.insert(state.to_string(), Some(character_id));
+    }
+
+    pub fn contains(&self, state: &str) -> bool {
+        let states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.contains_key(state)
+    }
+
+    /// Character the login was started for, if any.
+    pub fn character(&self, state: &str) -> Option<i32> {
+        let states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.get(state).copied().flatten()
+    }
+
+    /// Removes the state, returning `false` when it wasn't pending.
+    pub fn take(&self, state: &str) -> bool {
+        let mut states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.remove(state).is_some()
+    }
+
+    // ends the login for good, the stored session included
+    pub(crate) fn cancel(&self, state: &str) {
+        if self.take(state) {
+            let mut cancelled = self.cancelled.lock().unwrap_or_else(PoisonError::into_inner);
+            cancelled.push(state.to_string());
+        }
+    }
+
+    pub(crate) fn take_cancelled(&self) -> Vec<String> {
+        let mut cancelled = self.cancelled.lock().unwrap_or_else(PoisonError::into_inner);
+        std::mem::take(&mut *cancelled)
+    }
+
+    pub fn len(&self) -> usize {
+        let states = self.states.lock().unwrap_or_else(PoisonError::into_inner);
+        states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct AuthService2 {
-    pub tx: Arc<Sender<(String, String)>>,
+    pub tx: Arc<Sender<CallbackResult>>,
+    /// When set, callbacks carrying a state that is not pending are rejected.
+    pub states: Option<PendingStates>,
+    pub pages: CallbackPages,
+    /// Names the character on the success page when the login was started for it.
+    pub characters: Option<Roster>,
+}
+
+impl AuthService2 {
+    pub fn new(
+        tx: Sender<CallbackResult>,
+        states: Option<PendingStates>,
+        pages: CallbackPages,
+    ) -> Self {
+        AuthService2 {
+            tx: Arc::new(tx),
+            states,
+            pages,
+            characters: None,
+        }
+    }
+
+    fn page(status: StatusCode, page: String) -> Response<Full<Bytes>> {
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Full::new(Bytes::from(page)))
+            .unwrap()
+    }
+
+    fn character_name(&self, state: &str) -> Option<String> {
+        let character_id = self.states.as_ref()?.character(state)?;
+        Some(self.characters.as_ref()?.get(character_id)?.name)
+    }
+
+    fn callback(&self, query: &str) -> Response<Full<Bytes>> {
+        let params = match CallbackParams::parse(query) {
+            Ok(params) => params,
+            Err(t_error) => {
+                return AuthService2::page(
+                    StatusCode::BAD_REQUEST,
+                    self.pages.render_failure(&t_error.to_string()),
+                );
+            }
+        };
+        let character = params.state.as_deref().and_then(|state| self.character_name(state));
+        match params.check(self.states.as_ref()) {
+            Ok(message) => {
+                // the caller only waits for the first one
+                let _ = self.tx.try_send(Ok(message));
+                AuthService2::page(
+                    StatusCode::OK,
+                    self.pages.render_success(character.as_deref()),
+                )
+            }
+            Err(Error::AuthStateReplayed) => AuthService2::page(
+                StatusCode::BAD_REQUEST,
+                self.pages.render_failure(INVALID_STATE),
+            ),
+            Err(Error::InvalidCallback(reason)) => AuthService2::page(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                self.pages.render_failure(&reason),
+            ),
+            Err(t_error) => {
+                let page = self.pages.render_failure(&t_error.to_string());
+                let _ = self.tx.try_send(Err(t_error));
+                AuthService2::page(StatusCode::OK, page)
+            }
+        }
+    }
 }
 
 impl Service<Request<IncomingBody>> for AuthService2 {
@@ -26,48 +317,7 @@ impl Service<Request<IncomingBody>> for AuthService2 {
 
     fn call(&self, req: Request<IncomingBody>) -> Self::Future {
         let res = match (req.method(), req.uri().path()) {
-            (&Method::GET, "/login") => {
-                let pnq = req.uri().path_and_query();
-                if let Some(params) = pnq.unwrap().query() {
-                    let mut message: (String, String) = (String::new(), String::new());
-                    let parameters = params.split('&').collect::<Vec<&str>>();
-                    for param in parameters {
-                        let p = param.split('=').collect::<Vec<&str>>();
-                        match p[0] {
-                            "code" => {
-                                message.0 = p[1].to_string();
-                            }
-                            "state" => {
-                                message.1 = p[1].to_string();
-                            }
-                            _ => (),
-                        }
-                    }
-                    if !message.0.is_empty() && !message.1.is_empty() {
-                        let rt = Builder::new_current_thread().enable_all().build().unwrap();
-                        let atx = Arc::clone(&self.tx);
-                        std::thread::spawn(move || {
-                            rt.block_on(async {
-                                let _res = atx.send(message).await;
-                            });
-                        });
-                        Ok(Response::builder()
-                            .status(StatusCode::OK)
-                            .body(Full::new(Bytes::from_static(CONFIRM)))
-                            .unwrap())
-                    } else {
-                        Ok(Response::builder()
-                            .status(StatusCode::UNPROCESSABLE_ENTITY)
-                            .body(Full::new(Bytes::from_static(NOT_VALID)))
-                            .unwrap())
-                    }
-                } else {
-                    Ok(Response::builder()
-                        .status(StatusCode::UNPROCESSABLE_ENTITY)
-                        .body(Full::new(Bytes::from_static(NOT_VALID)))
-                        .unwrap())
-                }
-            }
+            (&Method::GET, "/login") => Ok(self.callback(req.uri().query().unwrap_or_default())),
             _ => Ok(Response::builder()
                 .status(StatusCode::NOT_FOUND)
                 .body(Full::new(Bytes::from_static(NOT_VALID)))
@@ -0,0 +1,162 @@
+use rfesi::prelude::EsiError;
+use std::fmt;
+
+/// Errors returned by every fallible operation of the library.
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration is missing a value or has one that can't be used.
+    Config(String),
+    /// The player database failed or holds unexpected data.
+    Database(rusqlite::Error),
+    /// The player database schema is newer than the one this library knows.
+    SchemaVersion { found: u32, supported: u32 },
+    /// ESI or the SSO answered with a non-success HTTP status.
+    EsiStatus(u16),
+    /// Any other failure reported by the ESI client.
+    Esi(EsiError),
+    /// The character has no token stored, it needs to log in.
+    NotAuthenticated(i32),
+    /// The token of the character expired and there is no refresh token to renew it.
+    TokenExpired(i32),
+    /// The token or its claims could not be understood.
+    InvalidToken(String),
+    /// The OAuth `state` received doesn't match the one issued for the login.
+    AuthStateMismatch,
+    /// The OAuth `state` received is not pending, it was already used or never issued.
+    AuthStateReplayed,
+    /// The login the OAuth `state` was issued for is too old to be completed.
+    AuthStateExpired,
+    /// The login callback can't be understood, the reason says why.
+    InvalidCallback(String),
+    /// The SSO refused the request with an OAuth error, like a login whose consent
+    /// was denied or a revoked refresh token (`invalid_grant`).
+    Sso {
+        error: String,
+        description: Option<String>,
+    },
+    /// A login started for one character was completed with another one.
+    UnexpectedCharacter { expected: i32, found: i32 },
+    /// The SSO says the character now belongs to another account, its tokens were dropped.
+    CharacterTransferred(i32),
+    /// The SSO refused the tokens of the character for good, it has to log in again.
+    ReauthRequired(i32),
+    /// Nothing reached the login callback before the deadline.
+    Timeout,
+    /// The HTTP exchange failed before getting an answer.
+    Network(Box<dyn std::error::Error + Send + Sync>),
+    /// Local I/O failure, like binding the callback server port.
+    Io(std::io::Error),
+    /// A background database job panicked or was cancelled.
+    Task(tokio::task::JoinError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Config(reason) => write!(f, "Invalid configuration: {}", reason),
+            Error::Database(t_error) => write!(f, "Database error: {}", t_error),
+            Error::SchemaVersion { found, supported } => write!(
+                f,
+                "Database schema version {} is newer than the supported version {}",
+                found, supported
+            ),
+            Error::EsiStatus(status) => write!(f, "ESI answered with HTTP status {}", status),
+            Error::Esi(t_error) => write!(f, "ESI error: {}", t_error),
+            Error::NotAuthenticated(id) => write!(f, "Character {} has no stored token", id),
+            Error::TokenExpired(id) => write!(f, "Token of character {} expired", id),
+            Error::InvalidToken(reason) => write!(f, "Invalid token: {}", reason),
+            Error::AuthStateMismatch => write!(f, "OAuth state does not match the login request"),
+            Error::AuthStateReplayed => write!(f, "OAuth state is not pending or was already used"),
+            Error::AuthStateExpired => write!(f, "OAuth state belongs to an expired login"),
+            Error::InvalidCallback(reason) => write!(f, "Invalid login callback: {}", reason),
+            Error::Sso {
+                error,
+                description: Some(description),
+            } => write!(f, "SSO refused the request: {} ({})", error, description),
+            Error::Sso { error, .. } => write!(f, "SSO refused the request: {}", error),
+            Error::UnexpectedCharacter { expected, found } => write!(
+                f,
+                "Login was started for character {} but completed with {}",
+                expected, found
+            ),
+            Error::CharacterTransferred(id) => {
+                write!(f, "Character {} was transferred to another account", id)
+            }
+            Error::ReauthRequired(id) => write!(f, "Character {} has to log in again", id),
+            Error::Timeout => write!(f, "Timed out waiting for the login callback"),
+            Error::Network(t_error) => write!(f, "Network error: {}", t_error),
+            Error::Io(t_error) => write!(f, "I/O error: {}", t_error),
+            Error::Task(t_error) => write!(f, "Background task failed: {}", t_error),
+        }
+    }
+}
+
+impl Error {
+    /// Tells if retrying can't help: the SSO refused the tokens of the character
+    /// and only a new login brings them back.
+    pub fn needs_reauth(&self) -> bool {
+        match self {
+            Error::Sso { error, .. } => error == "invalid_grant",
+            Error::ReauthRequired(_) | Error::CharacterTransferred(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(t_error) => Some(t_error),
+            Error::Esi(t_error) => Some(t_error),
+            Error::Network(t_error) => Some(t_error.as_ref()),
+            Error::Io(t_error) => Some(t_error),
+            Error::Task(t_error) => Some(t_error),
+            _ => None,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::Database(value)
+    }
+}
+
+impl From<EsiError> for Error {
+    fn from(value: EsiError) -> Self {
+        match value {
+            EsiError::InvalidStatusCode(status) => Error::EsiStatus(status),
+            _ => Error::Esi(value),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<hyper::Error> for Error {
+    fn from(value: hyper::Error) -> Self {
+        Error::Network(Box::new(value))
+    }
+}
+
+impl From<hyper_util::client::legacy::Error> for Error {
+    fn from(value: hyper_util::client::legacy::Error) -> Self {
+        Error::Network(Box::new(value))
+    }
+}
+
+impl From<hyper::http::uri::InvalidUri> for Error {
+    fn from(value: hyper::http::uri::InvalidUri) -> Self {
+        Error::Network(Box::new(value))
+    }
+}
+
+impl From<tokio::task::JoinError> for Error {
+    fn from(value: tokio::task::JoinError) -> Self {
+        Error::Task(value)
+    }
+}
@@ -1,132 +1,190 @@
+use crate::auth_service::{
+    AuthService2, CallbackPages, CallbackParams, CallbackResult, PendingStates,
+};
+use crate::Error;
 use crate::objects::{Alliance, Character, Corporation};
-use chrono::DateTime;
-use hyper::body::Body;
+use chrono::{DateTime, Utc};
 use hyper_tls::HttpsConnector;
+use rfesi::groups::{
+    AllianceInfo, CharacterPortraitInfo, CharacterPublicInfo, CorporationPublicInfo, LocationInfo,
+};
 use rfesi::prelude::*;
-use rusqlite::vtab::array;
-use rusqlite::*;
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
 use std::path::Path;
+use std::time::Duration;
 use http_body_util::{BodyExt, Empty};
-use crate::objects::AuthData;
+use crate::objects::{AuthData, LoginOutcome, LoginSession};
 //use hyper::body::Bytes;
 use bytes::Bytes;
 use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::server::graceful::GracefulShutdown;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
 
+use self::connection_pool::{ConnectionPool, Database, PooledConnection};
 #[cfg(feature = "crypted-db")]
-use uuid::Uuid;
-
+use self::connection_pool::SharedKey;
 use self::player_database::PlayerDatabase;
+mod builder;
+pub use self::builder::EsiManagerBuilder;
+mod connection_pool;
+pub mod data;
+pub use self::data::{Data, Datasource};
 pub mod player_database;
+pub mod refresher;
+pub use self::refresher::{RefreshEvent, RefreshHandle, RefreshOptions};
+pub mod roster;
+pub use self::roster::Roster;
+pub mod scope;
+pub use self::scope::Scope;
+pub mod sso;
+pub use self::sso::{Sso, TokenClaims};
+
+#[cfg(feature = "crypted-db")]
+pub mod database_key;
+#[cfg(feature = "crypted-db")]
+pub use self::database_key::DatabaseKey;
+
+/// Time the login callback server waits for the SSO redirect before giving up.
+pub const AUTH_SERVER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time a started login can still be completed, across restarts too.
+pub const LOGIN_SESSION_TIMEOUT: Duration = Duration::from_secs(900);
 
 #[derive(Clone)]
 pub struct EsiManager {
     pub esi: Esi,
-    pub auth: AuthData,
-    pub characters: Vec<Character>,
+    pub sso: Sso,
+    pub characters: Roster,
     pub path: String,
+    /// URL the SSO sends the browser back to after a login.
+    pub callback_url: String,
+    /// Server the ESI calls of the manager ask for.
+    pub datasource: Datasource,
     pub active_character: Option<i32>,
+    pub pending_logins: PendingStates,
+    /// Pages shown by [`EsiManager::serve_login_callback`].
+    pub callback_pages: CallbackPages,
+    // scopes asked for on every login
+    scopes: Vec<Scope>,
+    pool: ConnectionPool,
+    // shared by clones like the pool, so a rekey reaches all of them
+    #[cfg(feature = "crypted-db")]
+    key: SharedKey,
 }
 
 impl EsiManager {
-    pub(crate) fn get_standard_connection(&self) -> Result<Connection, Error> {
-        let mut flags = OpenFlags::default();
-        flags.set(OpenFlags::SQLITE_OPEN_NO_MUTEX, false);
-        flags.set(OpenFlags::SQLITE_OPEN_FULL_MUTEX, true);
-        let connection = Connection::open_with_flags(self.path.clone(), flags)?;
-
-        // we add the carray module disguised as rarray in rusqlite
-        array::load_module(&connection)?;
-
-        let query = "PRAGMA journey_mode=WAL;";
-        let mut statement = connection.prepare(query)?;
-        let _ = statement.execute([])?;
-        
-
-        #[cfg(feature = "crypted-db")]
-        {
-            let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, "telescope".as_bytes());
-            let query = ["PRAGMA key = '", uuid.to_string().as_str(), "'"].concat();
-            let mut statement = connection.prepare(query.as_str())?;
-            let _ = statement.execute([])?;
+    pub(crate) fn database(&self) -> Database {
+        Database {
+            path: self.path.clone(),
+            pool: self.pool.clone(),
+            #[cfg(feature = "crypted-db")]
+            key: self.key.clone(),
         }
+    }
 
-        statement.finalize()?;
-        Ok(connection)
+    pub(crate) fn get_standard_connection(&self) -> Result<PooledConnection, Error> {
+        self.database().connection()
+    }
+
+    // runs the job on the blocking thread pool so the async runtime keeps going
+    async fn run_blocking<T, F>(&self, job: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let database = self.database();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = database.connection()?;
+            job(&mut conn)
+        })
+        .await?
     }
 
     // Alliance
     pub fn write_alliance(&mut self, alliance: &Alliance) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_write_alliance");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::upsert_alliance(&conn, alliance)
+    }
 
-        let players = PlayerDatabase::select_alliance(&conn, vec![alliance.id])?;
-        let rows = if !players.is_empty() {
-            PlayerDatabase::update_alliance(&conn, alliance)?
-        } else {
-            PlayerDatabase::insert_alliance(&conn, alliance)?
-        };
-        Ok(rows)
+    pub async fn write_alliance_async(&self, alliance: &Alliance) -> Result<usize, Error> {
+        let alliance = alliance.clone();
+        self.run_blocking(move |conn| PlayerDatabase::upsert_alliance(conn, &alliance))
+            .await
     }
 
-    pub fn read_alliance(
-        &mut self,
-        alliance_vec: Option<Vec<i32>>,
-    ) -> Result<Vec<Alliance>, Error> {
+    pub fn read_alliance(&self, alliance_vec: Option<Vec<i32>>) -> Result<Vec<Alliance>, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_read_alliance");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::select_alliance(&conn, alliance_vec.unwrap_or_default())
+    }
 
-        let result = if let Some(id_ally) = alliance_vec {
-            PlayerDatabase::select_alliance(&conn, id_ally)?
-        } else {
-            PlayerDatabase::select_alliance(&conn, vec![])?
-        };
-        Ok(result)
+    pub async fn read_alliance_async(
+        &self,
+        alliance_vec: Option<Vec<i32>>,
+    ) -> Result<Vec<Alliance>, Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::select_alliance(conn, alliance_vec.unwrap_or_default())
+        })
+        .await
     }
 
     pub fn remove_alliance(&mut self, alliance_vec: Option<Vec<i32>>) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_remove_alliance");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::delete_alliance(&conn, alliance_vec.unwrap_or_default())
+    }
 
-        let result = if let Some(id_ally) = alliance_vec {
-            PlayerDatabase::delete_alliance(&conn, id_ally)?
-        } else {
-            PlayerDatabase::delete_alliance(&conn, vec![])?
-        };
-        Ok(result)
+    pub async fn remove_alliance_async(
+        &self,
+        alliance_vec: Option<Vec<i32>>,
+    ) -> Result<usize, Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::delete_alliance(conn, alliance_vec.unwrap_or_default())
+        })
+        .await
     }
 
     // Corporation
     pub fn write_corporation(&mut self, corp: &Corporation) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_write_corporation");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::upsert_corporation(&conn, corp)
+    }
 
-        let corps = PlayerDatabase::select_corporation(&conn, vec![corp.id])?;
-        let rows = if !corps.is_empty() {
-            PlayerDatabase::update_corporation(&conn, corp)?
-        } else {
-            PlayerDatabase::insert_corporation(&conn, corp)?
-        };
-        Ok(rows)
+    pub async fn write_corporation_async(&self, corp: &Corporation) -> Result<usize, Error> {
+        let corp = corp.clone();
+        self.run_blocking(move |conn| PlayerDatabase::upsert_corporation(conn, &corp))
+            .await
     }
 
     pub fn read_corporation(
-        &mut self,
+        &self,
         corporation_vec: Option<Vec<i32>>,
     ) -> Result<Vec<Corporation>, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_read_corporation");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::select_corporation(&conn, corporation_vec.unwrap_or_default())
+    }
 
-        let result = if let Some(id_corp) = corporation_vec {
-            PlayerDatabase::select_corporation(&conn, id_corp)?
-        } else {
-            PlayerDatabase::select_corporation(&conn, vec![])?
-        };
-        Ok(result)
+    pub async fn read_corporation_async(
+        &self,
+        corporation_vec: Option<Vec<i32>>,
+    ) -> Result<Vec<Corporation>, Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::select_corporation(conn, corporation_vec.unwrap_or_default())
+        })
+        .await
     }
 
     pub fn remove_corporation(
@@ -135,180 +193,524 @@ impl EsiManager {
     ) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_remove_corporation");
-        let conn = self.get_standard_connection().unwrap();
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::delete_corporation(&conn, corporation_vec.unwrap_or_default())
+    }
 
-        let result = if let Some(id_ally) = corporation_vec {
-            PlayerDatabase::delete_corporation(&conn, id_ally)?
-        } else {
-            PlayerDatabase::delete_corporation(&conn, vec![])?
-        };
-        Ok(result)
+    pub async fn remove_corporation_async(
+        &self,
+        corporation_vec: Option<Vec<i32>>,
+    ) -> Result<usize, Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::delete_corporation(conn, corporation_vec.unwrap_or_default())
+        })
+        .await
     }
 
     //Characters
+    /// Writes the character along with its corporation, alliance and token
+    /// in a single transaction.
     pub fn write_character(&mut self, char: &Character) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_write_character");
 
-        let conn = self.get_standard_connection().unwrap();
+        self.write_characters(std::slice::from_ref(char))
+    }
 
-        // first we need to assure that Corporation and alliance exists on database
-        if let Some(corp) = &char.corp {
-            let _ = self.write_corporation(corp)?;
-        }
+    pub async fn write_character_async(&self, char: &Character) -> Result<usize, Error> {
+        self.write_characters_async(std::slice::from_ref(char)).await
+    }
 
-        if let Some(alliance) = &char.alliance {
-            let _ = self.write_alliance(alliance)?;
-        }
+    /// Writes all the characters in a single transaction, either all of them
+    /// are stored or none is.
+    pub fn write_characters(&mut self, chars: &[Character]) -> Result<usize, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("esi_write_characters");
 
-        let players = PlayerDatabase::select_characters(&conn, vec![char.id])?;
-        let rows = if !players.is_empty() {
-            PlayerDatabase::update_character(&conn, char)?
-        } else {
-            PlayerDatabase::insert_character(&conn, char)?
-        };
-        Ok(rows)
+        let mut conn = self.get_standard_connection()?;
+        PlayerDatabase::write_characters(&mut conn, chars)
+    }
+
+    pub async fn write_characters_async(&self, chars: &[Character]) -> Result<usize, Error> {
+        let chars = chars.to_vec();
+        self.run_blocking(move |conn| PlayerDatabase::write_characters(conn, &chars))
+            .await
     }
 
-    pub fn read_characters(&mut self, char_vec: Option<Vec<i32>>) -> Result<Vec<Character>, Error> {
+    pub fn read_characters(&self, char_vec: Option<Vec<i32>>) -> Result<Vec<Character>, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_read_characters");
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::select_characters(&conn, char_vec.unwrap_or_default())
+    }
 
-        let conn = self.get_standard_connection().unwrap();
-
-        let result;
-        if let Some(id_chars) = char_vec {
-            result = PlayerDatabase::select_characters(&conn, id_chars)?;
-        } else {
-            result = PlayerDatabase::select_characters(&conn, vec![])?;
-        };
-        Ok(result)
+    pub async fn read_characters_async(
+        &self,
+        char_vec: Option<Vec<i32>>,
+    ) -> Result<Vec<Character>, Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::select_characters(conn, char_vec.unwrap_or_default())
+        })
+        .await
     }
 
     pub fn remove_characters(&mut self, char_vec: Option<Vec<i32>>) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_remove_character");
-        let conn = self.get_standard_connection().unwrap();
+        let ids = char_vec.unwrap_or_default();
+        let conn = self.get_standard_connection()?;
+        let rows = PlayerDatabase::delete_characters(&conn, ids.clone())?;
+        self.forget_characters(&ids);
+        Ok(rows)
+    }
 
-        let result = if let Some(id_chars) = char_vec {
-            PlayerDatabase::delete_characters(&conn, id_chars)?
-        } else {
-            PlayerDatabase::delete_characters(&conn, vec![])?
-        };
-        Ok(result)
+    pub async fn remove_characters_async(
+        &self,
+        char_vec: Option<Vec<i32>>,
+    ) -> Result<usize, Error> {
+        let ids = char_vec.unwrap_or_default();
+        let deleted = ids.clone();
+        let rows = self
+            .run_blocking(move |conn| PlayerDatabase::delete_characters(conn, deleted))
+            .await?;
+        self.forget_characters(&ids);
+        Ok(rows)
     }
 
+    // keeps the shared roster in line with the database
+    fn forget_characters(&self, ids: &[i32]) {
+        for id in ids {
+            self.characters.remove(*id);
+        }
+    }
+
+    /// Opens the player database at `database_path`, creating it when missing.
+    ///
+    /// With the `crypted-db` feature the database is encrypted with the key shared
+    /// by every install, use [`EsiManager::new_with_key`] to supply your own.
     pub fn new(
         useragent: &str,
         client_id: &str,
-        _client_secret: &str,
+        client_secret: &str,
         callback_url: &str,
-        scope: Vec<&str>,
+        scope: Vec<Scope>,
         database_path: String,
-    ) -> Self {
-        #[cfg(not(feature = "native-auth-flow"))]
-        let esi = EsiBuilder::new()
+    ) -> Result<Self, Error> {
+        EsiManagerBuilder::new()
             .user_agent(useragent)
             .client_id(client_id)
-            .client_secret(_client_secret)
+            .client_secret(client_secret)
             .callback_url(callback_url)
-            .scope(scope.join(" ").as_str())
+            .scopes(scope)
+            .database_path(&database_path)
             .build()
-            .unwrap();
+    }
 
-        #[cfg(feature = "native-auth-flow")]
-        let esi = EsiBuilder::new()
+    /// Same as [`EsiManager::new`] but encrypting the database with `key`.
+    #[cfg(feature = "crypted-db")]
+    pub fn new_with_key(
+        useragent: &str,
+        client_id: &str,
+        client_secret: &str,
+        callback_url: &str,
+        scope: Vec<Scope>,
+        database_path: String,
+        key: DatabaseKey,
+    ) -> Result<Self, Error> {
+        EsiManagerBuilder::new()
             .user_agent(useragent)
             .client_id(client_id)
+            .client_secret(client_secret)
             .callback_url(callback_url)
-            .enable_application_authentication(true)
-            .scope(scope.join(" ").as_str())
+            .scopes(scope)
+            .database_path(&database_path)
+            .database_key(key)
             .build()
-            .unwrap();
+    }
+
+    /// Builder to configure the manager beyond what [`EsiManager::new`] takes.
+    pub fn builder() -> EsiManagerBuilder {
+        EsiManagerBuilder::new()
+    }
 
-        let mut obj = EsiManager {
+    fn from_esi(
+        esi: Esi,
+        sso: Sso,
+        callback_url: &str,
+        scopes: Vec<Scope>,
+        database_path: String,
+    ) -> Self {
+        EsiManager {
             esi,
-            auth: AuthData::new(),
-            characters: Vec::new(),
+            sso,
+            characters: Roster::new(),
             path: database_path,
+            callback_url: callback_url.to_string(),
+            datasource: Datasource::default(),
             active_character: None,
-        };
+            pending_logins: PendingStates::new(),
+            callback_pages: CallbackPages::default(),
+            scopes,
+            pool: ConnectionPool::new(),
+            #[cfg(feature = "crypted-db")]
+            key: SharedKey::new(DatabaseKey::legacy()),
+        }
+    }
 
+    // creates or upgrades the database and loads the characters stored on it
+    fn open_database(self) -> Result<Self, Error> {
         // Path needs to be checked before invoking rusqlite to be effective
-        let temp_path = Path::new(&obj.path);
+        let temp_path = Path::new(&self.path);
         if !temp_path.exists() || !temp_path.is_file() {
-            // TODO: migration database schema goes here
-            let conn = obj.get_standard_connection();
-            let _ = PlayerDatabase::create_database(&conn.unwrap());
-            let _ = PlayerDatabase::migrate_database();
+            let mut conn = self.get_standard_connection()?;
+            PlayerDatabase::create_database(&conn)?;
+            PlayerDatabase::migrate_database(&mut conn)?;
         } else {
-            let conn = obj.get_standard_connection();
-            // load existing players
-            if let Ok(chars) = PlayerDatabase::select_characters(&conn.as_ref().unwrap(), vec![]) {
-                obj.characters = chars;
-                if !obj.characters.is_empty() {
-                    obj.auth = PlayerDatabase::select_auth(&conn.as_ref().unwrap()).expect("Invalid Authetication data");
+            let mut conn = self.get_standard_connection()?;
+            PlayerDatabase::migrate_database(&mut conn)?;
+            // load existing players along with their tokens
+            self.characters
+                .replace_all(PlayerDatabase::select_characters(&conn, vec![])?);
+
+            // logins started before a restart can still complete
+            PlayerDatabase::delete_login_sessions_before(&conn, Utc::now() - LOGIN_SESSION_TIMEOUT)?;
+            for session in PlayerDatabase::select_login_sessions(&conn)? {
+                match session.character_id {
+                    Some(character_id) => self.pending_logins.insert_for(&session.state, character_id),
+                    None => self.pending_logins.insert(&session.state),
                 }
             }
         }
-        obj
+        Ok(self)
     }
 
-    pub async fn get_location(&mut self, player_id: i32) -> Result<i32, String> {
+    /// Re-encrypts the database with `key`, following connections use it.
+    #[cfg(feature = "crypted-db")]
+    pub fn rekey(&mut self, key: DatabaseKey) -> Result<(), Error> {
         #[cfg(feature = "puffin")]
-        puffin::profile_scope!("esi_get_location");
+        puffin::profile_scope!("esi_rekey");
 
-        if !self.valid_token().await {
-            return Err(String::from("Invalid Token"));
-        }
+        let conn = self.get_standard_connection()?;
+        // connections opened meanwhile by clones wait for the new key
+        let mut current = self.key.write();
+        conn.execute_batch(key.pragma("rekey")?.as_str())?;
+        drop(conn);
+        *current = key;
+        // the other connections still hold the old key
+        self.pool.clear();
+        Ok(())
+    }
 
-        match self.esi.group_location().get_location(player_id).await {
-            Ok(location) => {
-                let player_location = location.solar_system_id;
-                return Ok(player_location);
-            },
-            Err(t_error) => {
-                return Err(t_error.to_string());
-            }
+    /// Converts a database encrypted with the key shared by every install to `key`.
+    ///
+    /// Returns `false` without touching the file when it can't be opened with the
+    /// legacy key, e.g. because it was already converted. A missing file is an error.
+    #[cfg(feature = "crypted-db")]
+    pub fn migrate_legacy_key(database_path: &str, key: &DatabaseKey) -> Result<bool, Error> {
+        // an empty database would read fine with any key
+        let flags = rusqlite::OpenFlags::SQLITE_OPEN_READ_WRITE
+            | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = Connection::open_with_flags(database_path, flags)?;
+        conn.execute_batch(DatabaseKey::legacy().pragma("key")?.as_str())?;
+        let readable = conn
+            .query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get::<usize, i32>(0))
+            .is_ok();
+        if readable {
+            conn.execute_batch(key.pragma("rekey")?.as_str())?;
         }
+        Ok(readable)
+    }
+
+    pub async fn get_location(&mut self, character_id: i32) -> Result<i32, Error> {
+        self.ensure_token(character_id).await?;
+        let location: LocationInfo = self
+            .esi_get(
+                "get_characters_character_id_location",
+                ("{character_id}", character_id),
+                RequestType::Authenticated,
+            )
+            .await?;
+        Ok(location.solar_system_id)
+    }
+
+    // GET on an ESI operation taking a single id, asking for the datasource of the manager
+    async fn esi_get<T: DeserializeOwned>(
+        &mut self,
+        op_id: &str,
+        (name, id): (&str, i32),
+        request_type: RequestType,
+    ) -> Result<T, Error> {
+        let path = self
+            .esi
+            .try_get_endpoint_for_op_id(op_id)
+            .await?
+            .replace(name, &id.to_string());
+        let query = [("datasource", self.datasource.as_str())];
+        Ok(self
+            .esi
+            .query("GET", request_type, &path, Some(&query), None)
+            .await?)
     }
 
-    pub async fn valid_token(&self) -> bool {
+    /// Checks that the stored token of the character exists and won't expire in the next seconds.
+    pub async fn valid_token(&self, character_id: i32) -> bool {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("token_expired");
         let mut result = false;
-        if self.esi.access_expiration.is_none() || self.esi.access_token.is_none() || self.esi.refresh_token.is_none() {
-            return result;
-        }
-        if !self.auth.token.is_empty() && self.auth.expiration.is_some() && !self.auth.refresh_token.is_empty() {
-            let current_datetime = chrono::Utc::now();
-            //if auth.expiration =
-            let offset =  self.auth.expiration.unwrap() - current_datetime;
-            if offset.num_seconds()>= 20 {
-                result = true;
+        if let Some(auth) = self.characters.auth(character_id) {
+            if let Some(expiration) = auth.expiration {
+                if !auth.token.is_empty() && !auth.refresh_token.is_empty() {
+                    let current_datetime = chrono::Utc::now();
+                    let offset = expiration - current_datetime;
+                    if offset.num_seconds() >= 20 {
+                        result = true;
+                    }
+                }
             }
         }
         result
     }
 
-    pub async fn refresh_token(&mut self) -> Result<usize,String> {
-        if let Err(t_error) = self.esi.refresh_access_token(Some(&self.auth.refresh_token)).await {
-            return Err(t_error.to_string());
+    /// Loads a usable token of the character into `self.esi`, refreshing it first
+    /// when it is about to expire. Authenticated calls of the manager do it on
+    /// their own, call it before using `self.esi` directly.
+    pub async fn ensure_token(&mut self, character_id: i32) -> Result<(), Error> {
+        if !self.valid_token(character_id).await {
+            let lock = self.characters.refresh_lock(character_id);
+            let _guard = lock.lock().await;
+            // whoever held the lock before may have refreshed it already
+            self.reload_auth(character_id).await?;
+            if !self.valid_token(character_id).await {
+                self.refresh_unlocked(character_id).await?;
+            }
+        }
+        self.use_character_token(character_id);
+        Ok(())
+    }
+
+    pub async fn refresh_token(&mut self, character_id: i32) -> Result<usize, Error> {
+        let lock = self.characters.refresh_lock(character_id);
+        let _guard = lock.lock().await;
+        self.reload_auth(character_id).await?;
+        self.refresh_unlocked(character_id).await
+    }
+
+    // refreshes the token unless it stays valid past `deadline`, tells if it did
+    async fn refresh_expiring(
+        &mut self,
+        character_id: i32,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let lock = self.characters.refresh_lock(character_id);
+        let _guard = lock.lock().await;
+        self.reload_auth(character_id).await?;
+        let expiring = match self.characters.auth(character_id) {
+            Some(auth) => auth.expiration.is_none_or(|expiration| expiration <= deadline),
+            None => return Err(Error::NotAuthenticated(character_id)),
+        };
+        if expiring {
+            self.refresh_unlocked(character_id).await?;
+        }
+        Ok(expiring)
+    }
+
+    /// Validates the stored access token of the character without asking the SSO,
+    /// see [`Sso::validate_token`].
+    pub async fn token_claims(&self, character_id: i32) -> Result<TokenClaims, Error> {
+        match self.characters.auth(character_id) {
+            Some(auth) => self.sso.validate_token(&auth.token, Some(character_id)).await,
+            None => Err(Error::NotAuthenticated(character_id)),
         }
-        self.auth.token = self.esi.access_token.as_ref().unwrap().clone();
-        self.auth.expiration = chrono::DateTime::from_timestamp_millis(self.esi.access_expiration.unwrap());
-        self.auth.refresh_token = self.esi.refresh_token.as_ref().unwrap().clone();
-        if let Ok(conn) = self.get_standard_connection() {
-            if let Err(t_error) = PlayerDatabase::update_auth(&conn, &self.auth){
-                return Err(t_error.to_string());
+    }
+
+    /// Logs the character out: revokes its refresh token at the SSO and forgets
+    /// the stored tokens, removing the character too when `remove_character` is set.
+    ///
+    /// When it was the active character, the next one still logged in takes its place.
+    pub async fn logout(&mut self, character_id: i32, remove_character: bool) -> Result<(), Error> {
+        // a refresh in flight would rotate the token we are about to revoke
+        let lock = self.characters.refresh_lock(character_id);
+        let _guard = lock.lock().await;
+        self.reload_auth(character_id).await?;
+        if let Some(auth) = self.characters.auth(character_id) {
+            if !auth.refresh_token.is_empty() {
+                self.sso.revoke(&auth.refresh_token).await?;
             }
         }
-        return Ok(0);
-        
+
+        self.run_blocking(move |conn| PlayerDatabase::delete_auth(conn, vec![character_id]))
+            .await?;
+        self.characters.set_auth(character_id, None);
+        if remove_character {
+            self.remove_characters_async(Some(vec![character_id])).await?;
+        }
+
+        if self.active_character == Some(character_id) {
+            self.active_character = self
+                .characters
+                .list()
+                .iter()
+                .find(|char| char.id != character_id && char.auth.is_some())
+                .map(|char| char.id);
+        }
+        Ok(())
+    }
+
+    // picks up the token stored by another manager sharing the database
+    async fn reload_auth(&mut self, character_id: i32) -> Result<(), Error> {
+        let stored = self
+            .run_blocking(move |conn| PlayerDatabase::select_auth(conn, character_id))
+            .await?;
+        if stored.is_some() {
+            self.characters.set_auth(character_id, stored);
+        }
+        Ok(())
+    }
+
+    // the caller must hold the refresh lock of the character
+    async fn refresh_unlocked(&mut self, character_id: i32) -> Result<usize, Error> {
+        if self.characters.get(character_id).is_some_and(|char| char.needs_reauth) {
+            return Err(Error::ReauthRequired(character_id));
+        }
+        let refresh_token = match self.characters.auth(character_id) {
+            Some(auth) => auth.refresh_token,
+            None => return Err(Error::NotAuthenticated(character_id)),
+        };
+        // nothing to renew it with, the SSO would only refuse it
+        if refresh_token.is_empty() {
+            return Err(Error::TokenExpired(character_id));
+        }
+        let mut auth = match self.sso.refresh(&refresh_token).await {
+            Ok(auth) => auth,
+            Err(t_error) if t_error.needs_reauth() => {
+                self.require_reauth(character_id).await?;
+                return Err(Error::ReauthRequired(character_id));
+            }
+            Err(t_error) => return Err(t_error),
+        };
+        // the old refresh token is spent, the new one is saved before anything else
+        // can fail or it would be lost and the next refresh refused
+        auth.scopes = self
+            .characters
+            .auth(character_id)
+            .map(|auth| auth.scopes)
+            .unwrap_or_default();
+        self.store_auth(character_id, auth.clone()).await?;
+
+        let claims = self.sso.validate_token(&auth.token, None).await?;
+        if claims.character_id != character_id {
+            self.drop_auth(character_id).await?;
+            return Err(Error::InvalidToken(format!(
+                "token belongs to character {}",
+                claims.character_id
+            )));
+        }
+        self.check_owner(character_id, &claims.owner).await?;
+        if claims.scopes != auth.scopes {
+            auth.scopes = claims.scopes;
+            self.store_auth(character_id, auth).await?;
+        }
+        self.use_character_token(character_id);
+        Ok(0)
+    }
+
+    async fn store_auth(&self, character_id: i32, auth: AuthData) -> Result<(), Error> {
+        let stored = auth.clone();
+        self.run_blocking(move |conn| PlayerDatabase::upsert_auth(conn, character_id, &stored))
+            .await?;
+        self.characters.set_auth(character_id, Some(auth));
+        Ok(())
+    }
+
+    async fn drop_auth(&self, character_id: i32) -> Result<(), Error> {
+        self.run_blocking(move |conn| PlayerDatabase::delete_auth(conn, vec![character_id]))
+            .await?;
+        self.characters.set_auth(character_id, None);
+        Ok(())
+    }
+
+    // the tokens are dead, nothing is tried again until the character logs in
+    async fn require_reauth(&self, character_id: i32) -> Result<(), Error> {
+        self.run_blocking(move |conn| {
+            PlayerDatabase::delete_auth(conn, vec![character_id])?;
+            PlayerDatabase::set_needs_reauth(conn, character_id, true)
+        })
+        .await?;
+        self.characters.update(character_id, |char| {
+            char.auth = None;
+            char.needs_reauth = true;
+        });
+        Ok(())
+    }
+
+    /// Characters whose tokens the SSO refused for good, they have to log in again.
+    pub fn characters_needing_reauth(&self) -> Vec<i32> {
+        self.characters
+            .list()
+            .iter()
+            .filter(|char| char.needs_reauth)
+            .map(|char| char.id)
+            .collect()
+    }
+
+    // a token issued to another account than the one stored means the character was
+    // transferred, the tokens we hold belong to the previous owner
+    async fn check_owner(&mut self, character_id: i32, owner: &str) -> Result<(), Error> {
+        let known = self.characters.get(character_id).and_then(|char| char.owner);
+        if known.as_deref() == Some(owner) {
+            return Ok(());
+        }
+        let transferred = known.is_some();
+        let stored = owner.to_string();
+        self.run_blocking(move |conn| {
+            if transferred {
+                PlayerDatabase::delete_auth(conn, vec![character_id])?;
+                PlayerDatabase::set_transferred(conn, character_id, true)?;
+            }
+            PlayerDatabase::update_owner(conn, character_id, &stored)
+        })
+        .await?;
+        self.characters.update(character_id, |char| {
+            char.owner = Some(owner.to_string());
+            if transferred {
+                char.auth = None;
+                char.transferred = true;
+            }
+        });
+        if transferred {
+            return Err(Error::CharacterTransferred(character_id));
+        }
+        Ok(())
+    }
+
+    /// Clears the [`Character::transferred`] flag once the application dealt with it.
+    pub async fn acknowledge_transfer(&self, character_id: i32) -> Result<(), Error> {
+        self.run_blocking(move |conn| PlayerDatabase::set_transferred(conn, character_id, false))
+            .await?;
+        self.characters.update(character_id, |char| char.transferred = false);
+        Ok(())
+    }
+
+    // loads the stored token of the character into the ESI client
+    fn use_character_token(&mut self, character_id: i32) {
+        if let Some(auth) = self.characters.auth(character_id) {
+            self.esi.access_token = Some(auth.token);
+            self.esi.access_expiration = auth.expiration.map(|date| date.timestamp_millis());
+            self.esi.refresh_token = Some(auth.refresh_token);
+        }
+    }
+
+    // tokens currently held by the ESI client
+    fn current_auth(&self) -> AuthData {
+        let mut auth = AuthData::new();
+        auth.token = self.esi.access_token.clone().unwrap_or_default();
+        auth.refresh_token = self.esi.refresh_token.clone().unwrap_or_default();
+        auth.expiration = self.esi.access_expiration.and_then(DateTime::from_timestamp_millis);
+        auth
     }
 
     #[tokio::main(flavor = "current_thread")]
-    pub async fn get_player_photo(url: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    pub async fn get_player_photo(url: &str) -> Result<Vec<u8>, Error> {
         #[cfg(feature = "puffin")]
         puffin::profile_scope!("esi_get_player_photo");
 
@@ -320,8 +722,8 @@ impl EsiManager {
         //assert_eq!(res.status(), 200);
         let mut photo:Vec<u8> = vec![];
         if res.status() == 200 {
-            while !res.is_end_stream() {
-                if let Some(data) = res.body_mut().frame().await.unwrap().expect("No data").data_mut() {
+            while let Some(frame) = res.body_mut().frame().await {
+                if let Some(data) = frame?.data_ref() {
                     photo.extend_from_slice(data.as_ref());
                 }
             }
@@ -329,80 +731,321 @@ impl EsiManager {
         Ok(photo)
     }
 
+    /// Builds the SSO authorize URL and registers its `state` as a pending login,
+    /// several logins can be pending at the same time.
+    pub fn get_authorize_url(
+        &self,
+    ) -> Result<AuthenticationInformation, Error> {
+        let auth_info = self.esi.get_authorize_url()?;
+        self.start_login(&auth_info, None, self.scopes.clone())?;
+        Ok(auth_info)
+    }
+
+    // stores the login so it can be completed after a restart too
+    fn start_login(
+        &self,
+        auth_info: &AuthenticationInformation,
+        character_id: Option<i32>,
+        scopes: Vec<Scope>,
+    ) -> Result<(), Error> {
+        let session = LoginSession {
+            state: auth_info.state.clone(),
+            verifier: auth_info.pkce_verifier.clone(),
+            character_id,
+            scopes,
+            created_at: Utc::now(),
+        };
+        let conn = self.get_standard_connection()?;
+        PlayerDatabase::insert_login_session(&conn, &session)?;
+        match character_id {
+            Some(character_id) => self.pending_logins.insert_for(&session.state, character_id),
+            None => self.pending_logins.insert(&session.state),
+        }
+        Ok(())
+    }
+
+    /// Scopes in `required` the character hasn't granted yet.
+    pub fn missing_scopes(&self, character_id: i32, required: &[Scope]) -> Vec<Scope> {
+        let granted = self
+            .characters
+            .auth(character_id)
+            .map(|auth| auth.scopes)
+            .unwrap_or_default();
+        required
+            .iter()
+            .filter(|scope| !granted.contains(scope))
+            .cloned()
+            .collect()
+    }
+
+    /// Builds an authorize URL asking the character for `required` on top of the
+    /// scopes it already granted. The login only completes for that character.
+    pub fn get_upgrade_url(
+        &self,
+        character_id: i32,
+        required: &[Scope],
+    ) -> Result<AuthenticationInformation, Error> {
+        let mut scopes = self.scopes.clone();
+        let granted = self.characters.auth(character_id).map(|auth| auth.scopes);
+        for scope in granted.unwrap_or_default().iter().chain(required) {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+
+        let mut auth_info = self.esi.get_authorize_url()?;
+        // the ESI client always asks for the scopes it was built with
+        let (base, query) = auth_info
+            .authorization_url
+            .split_once('?')
+            .unwrap_or((auth_info.authorization_url.as_str(), ""));
+        let mut url = form_urlencoded::Serializer::new(format!("{}?", base));
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            if key == "scope" {
+                url.append_pair("scope", &Scope::join(&scopes));
+            } else {
+                url.append_pair(&key, &value);
+            }
+        }
+        auth_info.authorization_url = url.finish();
+        self.start_login(&auth_info, Some(character_id), scopes)?;
+        Ok(auth_info)
+    }
+
+    /// Launches the local OAuth callback server on `127.0.0.1:port` and waits
+    /// for the SSO redirect, returning the `(code, state)` pair it carries.
+    pub async fn launch_auth_server(
+        port: u16,
+    ) -> Result<(String, String), Error> {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        EsiManager::launch_auth_server_on(addr, AUTH_SERVER_TIMEOUT, None).await
+    }
+
+    /// Same as [`EsiManager::launch_auth_server`] but binding to `addr`, giving up
+    /// once `timeout` has elapsed without a valid callback.
+    ///
+    /// When `states` is given, callbacks with a state that is not pending get an
+    /// error page and the server keeps waiting. The server stops accepting
+    /// connections as soon as the first valid callback arrives and lets
+    /// in-flight responses finish before returning.
+    pub async fn launch_auth_server_on(
+        addr: SocketAddr,
+        timeout: Duration,
+        states: Option<PendingStates>,
+    ) -> Result<(String, String), Error> {
+        EsiManager::serve_auth(addr, timeout, states, CallbackPages::default(), None).await
+    }
+
+    /// Same as [`EsiManager::launch_auth_server_on`] but only accepting the logins
+    /// started by this manager and answering with its [`EsiManager::callback_pages`].
+    pub async fn serve_login_callback(
+        &self,
+        addr: SocketAddr,
+        timeout: Duration,
+    ) -> Result<(String, String), Error> {
+        let states = Some(self.pending_logins.clone());
+        let pages = self.callback_pages.clone();
+        let result =
+            EsiManager::serve_auth(addr, timeout, states, pages, Some(self.characters.clone())).await;
+        self.forget_cancelled_logins().await?;
+        result
+    }
+
+    // logins the SSO ended with an error must not come back on the next start
+    async fn forget_cancelled_logins(&self) -> Result<(), Error> {
+        let states = self.pending_logins.take_cancelled();
+        if !states.is_empty() {
+            self.run_blocking(move |conn| PlayerDatabase::delete_login_sessions(conn, states))
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn serve_auth(
+        addr: SocketAddr,
+        timeout: Duration,
+        states: Option<PendingStates>,
+        pages: CallbackPages,
+        characters: Option<Roster>,
+    ) -> Result<(String, String), Error> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, mut rx) = mpsc::channel::<CallbackResult>(1);
+        let mut service = AuthService2::new(tx, states, pages);
+        service.characters = characters;
+        let graceful = GracefulShutdown::new();
+
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let result = loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let conn = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service.clone());
+                    let conn = graceful.watch(conn);
+                    tokio::spawn(async move {
+                        let _ = conn.await;
+                    });
+                }
+                message = rx.recv() => {
+                    break message.unwrap_or_else(|| Err(Error::Network("Login callback channel closed".into())));
+                }
+                _ = &mut deadline => {
+                    break Err(Error::Timeout);
+                }
+            }
+        };
+        drop(listener);
+
+        // browsers keep the connection alive, so don't wait forever for them
+        let _ = tokio::time::timeout(Duration::from_secs(1), graceful.shutdown()).await;
+        result
+    }
+
+    /// Completes the login `auth_info` was issued for, see [`Self::complete_login`]
+    /// to be told about transfers.
     pub async fn auth_user(
         &mut self,
-        _auth_info: AuthenticationInformation,
+        auth_info: AuthenticationInformation,
         oauth_data: (String, String),
-    ) -> Result<Option<Character>, Box<dyn std::error::Error + Send + Sync>> {
-        #[cfg(feature = "puffin")]
-        puffin::profile_scope!("esi_auth_user");
+    ) -> Result<Option<Character>, Error> {
+        // the state must be the one issued for this login and still pending
+        if oauth_data.1 != auth_info.state {
+            return Err(Error::AuthStateMismatch);
+        }
+        let outcome = self.complete_login(oauth_data).await?;
+        Ok(Some(outcome.into_character()))
+    }
 
-        #[cfg(not(feature = "native-auth-flow"))]
-        let verifier = None;
+    /// Completes a login without the callback server, for machines the SSO can't
+    /// redirect the browser to: the user opens the URL from [`Self::get_authorize_url`]
+    /// elsewhere and pastes back the redirect URL, or just its query string.
+    ///
+    /// The callback goes through the same checks as the ones the server receives.
+    pub async fn complete_pasted_login(&mut self, redirect: &str) -> Result<LoginOutcome, Error> {
+        let params = CallbackParams::from_redirect(redirect)?;
+        let result = params.check(Some(&self.pending_logins));
+        self.forget_cancelled_logins().await?;
+        let oauth_data = result?;
+        self.complete_login(oauth_data).await
+    }
 
-        #[cfg(feature = "native-auth-flow")]
-        let verifier = _auth_info.pkce_verifier;
+    /// Completes a login from the custom scheme URL the operating system hands over,
+    /// like `telescope://callback?code=..&state=..`, so desktop applications don't
+    /// need the local callback server. The URL must point to [`Self::callback_url`].
+    #[cfg(feature = "native-auth-flow")]
+    pub async fn complete_deep_link(&mut self, url: &str) -> Result<LoginOutcome, Error> {
+        let params = CallbackParams::from_deep_link(url, &self.callback_url)?;
+        let result = params.check(Some(&self.pending_logins));
+        self.forget_cancelled_logins().await?;
+        let oauth_data = result?;
+        self.complete_login(oauth_data).await
+    }
 
-        let claims_option = self
-            .esi
-            .authenticate(oauth_data.0.as_str(), verifier)
+    /// Completes the login the `state` was issued for, even by an earlier run of the
+    /// application: exchanges the code for the tokens and stores the character.
+    ///
+    /// A character logging in from another account than the stored one comes back
+    /// as [`LoginOutcome::Transferred`].
+    pub async fn complete_login(
+        &mut self,
+        oauth_data: (String, String),
+    ) -> Result<LoginOutcome, Error> {
+        let (code, state) = oauth_data;
+        self.pending_logins.take(&state);
+        let taken = state.clone();
+        let session = self
+            .run_blocking(move |conn| PlayerDatabase::take_login_session(conn, &taken))
+            .await?
+            .ok_or(Error::AuthStateReplayed)?;
+        if session.created_at + LOGIN_SESSION_TIMEOUT < Utc::now() {
+            return Err(Error::AuthStateExpired);
+        }
+        let expected = session.character_id;
+
+        self.esi.authenticate(code.as_str(), session.verifier).await?;
+        // never trust the token only because it came from the token endpoint
+        let token = self.esi.access_token.clone().unwrap_or_default();
+        let claims = self.sso.validate_token(&token, None).await?;
+        if let Some(expected) = expected.filter(|id| *id != claims.character_id) {
+            return Err(Error::UnexpectedCharacter {
+                expected,
+                found: claims.character_id,
+            });
+        }
+
+        let mut player = Character::new();
+        player.name = claims.name;
+        player.id = claims.character_id;
+        // another account logging the character in means it was sold since
+        let mut previous_owner = None;
+        if let Some(known) = self.characters.get(player.id) {
+            previous_owner = known.owner.filter(|owner| *owner != claims.owner);
+            player.transferred = known.transferred || previous_owner.is_some();
+        }
+        player.owner = Some(claims.owner);
+        let mut auth = self.current_auth();
+        auth.scopes = claims.scopes;
+        player.auth = Some(auth);
+        self.esi.update_spec().await?;
+        let public_info: CharacterPublicInfo = self
+            .esi_get(
+                "get_characters_character_id",
+                ("{character_id}", player.id),
+                RequestType::Public,
+            )
             .await?;
-        if let Some(claims) = claims_option {
-            
-            let mut player = Character::new();
-            //let data = claims.unwrap();
-            //character name
-            player.name = claims.name;
-            //character id
-            let split: Vec<&str> = claims.sub.split(':').collect();
-            player.id = split[2].parse::<i32>().unwrap();
-            if !self.valid_token().await {
-                self.auth.token = self.esi.access_token.as_ref().unwrap().to_string();
-                self.auth.refresh_token = self.esi.refresh_token.as_ref().unwrap().to_string();
-                //expiration Date
-
-                self.auth.expiration = DateTime::from_timestamp_millis(self.esi.access_expiration.unwrap());
-                /*let expiration: DateTime<Utc> =
-                    DateTime::parse_from_str(self.esi.access_token.as_ref().unwrap(), "%s")
-                        .unwrap()
-                        .into();*/
-                if let Ok(conn) =  self.get_standard_connection() {
-                    let _ =PlayerDatabase::update_auth(&conn, &self.auth);
-                }
-            }
-            self.esi.update_spec().await?;
-            let public_info = self
-                .esi
-                .group_character()
-                .get_public_info(player.id)
-                .await?;
-            let corp_info = self
-                .esi
-                .group_corporation()
-                .get_public_info(public_info.corporation_id)
+        let corp_info: CorporationPublicInfo = self
+            .esi_get(
+                "get_corporations_corporation_id",
+                ("{corporation_id}", public_info.corporation_id),
+                RequestType::Public,
+            )
+            .await?;
+        let corp = Corporation {
+            id: public_info.corporation_id,
+            name: corp_info.name,
+        };
+        player.corp = Some(corp);
+        if let Some(ally_id) = public_info.alliance_id {
+            let ally_info: AllianceInfo = self
+                .esi_get(
+                    "get_alliances_alliance_id",
+                    ("{alliance_id}", ally_id),
+                    RequestType::Public,
+                )
                 .await?;
-            let corp = Corporation {
-                id: public_info.corporation_id,
-                name: corp_info.name,
+            let ally = Alliance {
+                id: ally_id,
+                name: ally_info.name,
             };
-            player.corp = Some(corp);
-            if let Some(ally_id) = public_info.alliance_id {
-                let ally_info = self.esi.group_alliance().get_info(ally_id).await?;
-                let ally = Alliance {
-                    id: ally_id,
-                    name: ally_info.name,
-                };
-                player.alliance = Some(ally);
-            }
-            let player_portraits = self.esi.group_character().get_portrait(player.id).await?;
-            player.photo = Some(player_portraits.px128x128.unwrap());
-            let player_location = self.esi.group_location().get_location(player.id).await?;
-            player.location = player_location.solar_system_id;
-            
-            self.write_character(&player)?;
-            Ok(Some(player))
-        } else {
-            Ok(None)
+            player.alliance = Some(ally);
         }
+        let player_portraits: CharacterPortraitInfo = self
+            .esi_get(
+                "get_characters_character_id_portrait",
+                ("{character_id}", player.id),
+                RequestType::Public,
+            )
+            .await?;
+        player.photo = player_portraits.px128x128;
+        let player_location: LocationInfo = self
+            .esi_get(
+                "get_characters_character_id_location",
+                ("{character_id}", player.id),
+                RequestType::Authenticated,
+            )
+            .await?;
+        player.location = player_location.solar_system_id;
+
+        self.write_character_async(&player).await?;
+        self.characters.insert(player.clone());
+        Ok(match previous_owner {
+            Some(previous_owner) => LoginOutcome::Transferred {
+                character: player,
+                previous_owner,
+            },
+            None => LoginOutcome::LoggedIn(player),
+        })
     }
 }
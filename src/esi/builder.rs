@@ -0,0 +1,234 @@
+use super::data::{Data, Datasource};
+use super::{EsiManager, Scope, Sso};
+use crate::Error;
+use rfesi::prelude::{Esi, EsiBuilder};
+use std::time::Duration;
+
+#[cfg(feature = "crypted-db")]
+use super::connection_pool::SharedKey;
+#[cfg(feature = "crypted-db")]
+use super::DatabaseKey;
+
+/// Base URL of ESI, serving every datasource.
+pub const ESI_URL: &str = "https://esi.evetech.net/";
+
+/// Builds an [`EsiManager`], checking the configuration instead of panicking on it.
+///
+/// The user agent, client id, callback URL and database path are required, and so
+/// is the client secret unless the native flow is enabled.
+#[derive(Clone, Default)]
+pub struct EsiManagerBuilder {
+    user_agent: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    callback_url: Option<String>,
+    scopes: Vec<Scope>,
+    database_path: Option<String>,
+    #[cfg(feature = "crypted-db")]
+    database_key: Option<DatabaseKey>,
+    esi_url: Option<String>,
+    sso_url: Option<String>,
+    datasource: Datasource,
+    http_timeout: Option<Duration>,
+}
+
+impl EsiManagerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builder with the settings of `data`.
+    pub fn from_data(data: &Data) -> Result<Self, Error> {
+        let mut builder = EsiManagerBuilder::new()
+            .user_agent(&data.user_agent)
+            .client_id(&data.client_id)
+            .client_secret(&data.secret_key)
+            .callback_url(&data.callback_url)
+            .scopes(data.scopes.iter().map(|scope| Scope::from(scope.as_str())).collect())
+            .database_path(&data.database_path)
+            .datasource(data.datasource);
+        if let Some(url) = &data.esi_url {
+            builder = builder.esi_url(url);
+        }
+        if let Some(url) = &data.sso_url {
+            builder = builder.sso_url(url);
+        }
+        if let Some(seconds) = data.http_timeout {
+            builder = builder.http_timeout(Duration::from_secs(seconds));
+        }
+        match &data.database_key {
+            #[cfg(feature = "crypted-db")]
+            Some(key) => builder = builder.database_key(DatabaseKey::Passphrase(key.clone())),
+            #[cfg(not(feature = "crypted-db"))]
+            Some(_) => {
+                return Err(Error::Config(String::from(
+                    "database_key needs the crypted-db feature",
+                )))
+            }
+            None => {}
+        }
+        Ok(builder)
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.client_id = Some(client_id.to_string());
+        self
+    }
+
+    pub fn client_secret(mut self, client_secret: &str) -> Self {
+        self.client_secret = Some(client_secret.to_string());
+        self
+    }
+
+    pub fn callback_url(mut self, callback_url: &str) -> Self {
+        self.callback_url = Some(callback_url.to_string());
+        self
+    }
+
+    /// Scopes asked for on every login.
+    pub fn scopes(mut self, scopes: Vec<Scope>) -> Self {
+        self.scopes = scopes;
+        self
+    }
+
+    pub fn database_path(mut self, database_path: &str) -> Self {
+        self.database_path = Some(database_path.to_string());
+        self
+    }
+
+    /// Key encrypting the database, the legacy one when not set.
+    #[cfg(feature = "crypted-db")]
+    pub fn database_key(mut self, key: DatabaseKey) -> Self {
+        self.database_key = Some(key);
+        self
+    }
+
+    /// ESI base URL, the spec is expected at `<url>_latest/swagger.json`.
+    pub fn esi_url(mut self, esi_url: &str) -> Self {
+        self.esi_url = Some(esi_url.to_string());
+        self
+    }
+
+    /// SSO base URL, the one of the datasource when not set.
+    pub fn sso_url(mut self, sso_url: &str) -> Self {
+        self.sso_url = Some(sso_url.to_string());
+        self
+    }
+
+    /// Server the ESI calls ask for, and whose SSO is used unless
+    /// [`Self::sso_url`] says otherwise.
+    pub fn datasource(mut self, datasource: Datasource) -> Self {
+        self.datasource = datasource;
+        self
+    }
+
+    /// Longest an ESI or SSO request may take, one minute when not set.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Opens the database, creating or upgrading it, and loads its characters.
+    pub fn build(self) -> Result<EsiManager, Error> {
+        let user_agent = required(&self.user_agent, "user_agent")?;
+        let client_id = required(&self.client_id, "client_id")?;
+        let callback_url = required(&self.callback_url, "callback_url")?;
+        let database_path = required(&self.database_path, "database_path")?;
+        #[cfg(not(feature = "native-auth-flow"))]
+        let client_secret = required(&self.client_secret, "client_secret")?;
+        #[cfg(feature = "native-auth-flow")]
+        let client_secret = self.client_secret.as_deref().unwrap_or_default();
+
+        let sso_url = match &self.sso_url {
+            Some(url) => base_url(url, "sso_url")?,
+            None => self.datasource.sso_url().to_string(),
+        };
+        let esi = self.esi(user_agent, client_id, client_secret, callback_url, &sso_url)?;
+        let mut sso = Sso::new(client_id, client_secret);
+        sso.base_url = sso_url;
+        if let Some(timeout) = self.http_timeout {
+            sso.timeout = timeout;
+        }
+        let mut manager = EsiManager::from_esi(
+            esi,
+            sso,
+            callback_url,
+            self.scopes.clone(),
+            database_path.to_string(),
+        );
+        manager.datasource = self.datasource;
+        #[cfg(feature = "crypted-db")]
+        let manager = match self.database_key {
+            Some(key) => EsiManager {
+                key: SharedKey::new(key),
+                ..manager
+            },
+            None => manager,
+        };
+        manager.open_database()
+    }
+
+    fn esi(
+        &self,
+        user_agent: &str,
+        client_id: &str,
+        _client_secret: &str,
+        callback_url: &str,
+        sso_url: &str,
+    ) -> Result<Esi, Error> {
+        let esi_url = match &self.esi_url {
+            Some(url) => base_url(url, "esi_url")?,
+            None => ESI_URL.to_string(),
+        };
+        let spec_url = format!(
+            "{}_latest/swagger.json?datasource={}",
+            esi_url, self.datasource
+        );
+        let mut builder = EsiBuilder::new()
+            .user_agent(user_agent)
+            .client_id(client_id)
+            .callback_url(callback_url)
+            .scope(Scope::join(&self.scopes).as_str())
+            .base_api_url(&esi_url)
+            .spec_url(&spec_url)
+            .authorize_url(&format!("{}v2/oauth/authorize", sso_url))
+            .token_url(&format!("{}v2/oauth/token", sso_url));
+        #[cfg(not(feature = "native-auth-flow"))]
+        {
+            builder = builder.client_secret(_client_secret);
+        }
+        #[cfg(feature = "native-auth-flow")]
+        {
+            builder = builder.enable_application_authentication(true);
+        }
+        if let Some(timeout) = self.http_timeout {
+            builder = builder.http_timeout(Some(timeout.as_millis() as u64));
+        }
+        Ok(builder.build()?)
+    }
+}
+
+fn required<'a>(value: &'a Option<String>, name: &str) -> Result<&'a str, Error> {
+    match value.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => Ok(value),
+        _ => Err(Error::Config(format!("{} is missing", name))),
+    }
+}
+
+// base URLs are joined with paths, so they must be absolute and end with `/`
+fn base_url(url: &str, name: &str) -> Result<String, Error> {
+    let url = url.trim();
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(Error::Config(format!("{} must be an http or https URL", name)));
+    }
+    if url.ends_with('/') {
+        Ok(url.to_string())
+    } else {
+        Ok(format!("{}/", url))
+    }
+}
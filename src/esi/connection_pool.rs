@@ -0,0 +1,173 @@
+use crate::Error;
+use rusqlite::vtab::array;
+use rusqlite::{Connection, OpenFlags};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, PoisonError};
+#[cfg(feature = "crypted-db")]
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "crypted-db")]
+use super::DatabaseKey;
+
+/// Connections kept open for reuse, opening one means deriving the key again.
+pub(crate) const POOL_SIZE: usize = 4;
+
+struct PoolState {
+    path: String,
+    // bumped every time idle connections become unusable (new path or key)
+    generation: u64,
+    idle: Vec<Connection>,
+}
+
+/// Small pool of open connections to the player database, shared by clones.
+#[derive(Clone)]
+pub(crate) struct ConnectionPool {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new() -> Self {
+        ConnectionPool {
+            state: Arc::new(Mutex::new(PoolState {
+                path: String::new(),
+                generation: 0,
+                idle: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Takes an idle connection to `path`, if there is one.
+    pub(crate) fn take(&self, path: &str) -> Option<PooledConnection> {
+        let mut state = self.lock();
+        if state.path != path {
+            state.path = path.to_string();
+            state.idle.clear();
+            state.generation += 1;
+        }
+        let generation = state.generation;
+        state.idle.pop().map(|conn| PooledConnection {
+            conn: Some(conn),
+            generation,
+            pool: self.clone(),
+        })
+    }
+
+    /// Wraps a freshly opened connection so it comes back to the pool when dropped.
+    pub(crate) fn wrap(&self, conn: Connection) -> PooledConnection {
+        let generation = self.lock().generation;
+        PooledConnection {
+            conn: Some(conn),
+            generation,
+            pool: self.clone(),
+        }
+    }
+
+    /// Closes the idle connections, the ones in use are closed when dropped.
+    #[cfg(feature = "crypted-db")]
+    pub(crate) fn clear(&self) {
+        let mut state = self.lock();
+        state.idle.clear();
+        state.generation += 1;
+    }
+
+    fn release(&self, conn: Connection, generation: u64) {
+        let mut state = self.lock();
+        if state.generation == generation && state.idle.len() < POOL_SIZE {
+            state.idle.push(conn);
+        }
+    }
+}
+
+/// Key of the player database, shared by clones so a rekey reaches all of them.
+#[cfg(feature = "crypted-db")]
+#[derive(Clone)]
+pub(crate) struct SharedKey(Arc<RwLock<DatabaseKey>>);
+
+#[cfg(feature = "crypted-db")]
+impl SharedKey {
+    pub(crate) fn new(key: DatabaseKey) -> Self {
+        SharedKey(Arc::new(RwLock::new(key)))
+    }
+
+    pub(crate) fn read(&self) -> RwLockReadGuard<'_, DatabaseKey> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Held while the database is rekeyed, so no connection opens with the old key.
+    pub(crate) fn write(&self) -> RwLockWriteGuard<'_, DatabaseKey> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// What is needed to reach the player database, cheap to clone so blocking
+/// jobs can carry it to another thread.
+#[derive(Clone)]
+pub(crate) struct Database {
+    pub(crate) path: String,
+    pub(crate) pool: ConnectionPool,
+    #[cfg(feature = "crypted-db")]
+    pub(crate) key: SharedKey,
+}
+
+impl Database {
+    // hands out a pooled connection, opening a new one only when none is idle
+    pub(crate) fn connection(&self) -> Result<PooledConnection, Error> {
+        if let Some(conn) = self.pool.take(&self.path) {
+            return Ok(conn);
+        }
+        let connection = self.open()?;
+        Ok(self.pool.wrap(connection))
+    }
+
+    fn open(&self) -> Result<Connection, Error> {
+        let mut flags = OpenFlags::default();
+        flags.set(OpenFlags::SQLITE_OPEN_NO_MUTEX, false);
+        flags.set(OpenFlags::SQLITE_OPEN_FULL_MUTEX, true);
+        let connection = Connection::open_with_flags(self.path.clone(), flags)?;
+
+        // the key must be set before anything else touches the database,
+        // SQLCipher answers the pragma with a row so it can't go through execute
+        #[cfg(feature = "crypted-db")]
+        connection.execute_batch(self.key.read().pragma("key")?.as_str())?;
+
+        // we add the carray module disguised as rarray in rusqlite
+        array::load_module(&connection)?;
+
+        // the pragma answers with the resulting mode
+        let _mode: String = connection.query_row("PRAGMA journal_mode=WAL;", [], |row| row.get(0))?;
+        Ok(connection)
+    }
+}
+
+/// Connection borrowed from the [`ConnectionPool`].
+pub(crate) struct PooledConnection {
+    conn: Option<Connection>,
+    generation: u64,
+    pool: ConnectionPool,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection already released")
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().expect("connection already released")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.release(conn, self.generation);
+        }
+    }
+}
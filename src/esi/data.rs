@@ -1,22 +1,166 @@
+use super::sso::{SISI_SSO_URL, SSO_URL};
+use crate::Error;
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
 
-pub struct Data{
-    pub user_agent:String,
-    pub client_id:String,
+/// Prefix of the environment variables read by [`Data::from_env`].
+pub const ENV_PREFIX: &str = "WEBB_";
+
+/// EVE Online server the ESI calls and logins go to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Datasource {
+    #[default]
+    Tranquility,
+    /// Test server, with its own SSO and characters.
+    Singularity,
+}
+
+impl Datasource {
+    /// Name of the server as ESI knows it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Datasource::Tranquility => "tranquility",
+            Datasource::Singularity => "singularity",
+        }
+    }
+
+    /// Base URL of the SSO of the server.
+    pub fn sso_url(&self) -> &'static str {
+        match self {
+            Datasource::Tranquility => SSO_URL,
+            Datasource::Singularity => SISI_SSO_URL,
+        }
+    }
+}
+
+impl FromStr for Datasource {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "tranquility" => Ok(Datasource::Tranquility),
+            "singularity" => Ok(Datasource::Singularity),
+            _ => Err(Error::Config(format!("unknown datasource {}", value))),
+        }
+    }
+}
+
+impl fmt::Display for Datasource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Settings of an [`super::EsiManager`] as they come from a TOML file or the
+/// environment, turned into a manager by [`super::EsiManagerBuilder::from_data`].
+///
+/// ```toml
+/// user_agent = "telescope/v0"
+/// client_id = "a4b4a52e65fe4dce95eec1fab224407c"
+/// secret_key = "..."
+/// callback_url = "http://localhost:4500/login"
+/// scopes = ["publicData", "esi-location.read_location.v1"]
+/// database_path = "telescope.db"
+/// datasource = "tranquility"
+/// http_timeout = 30
+/// ```
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Data {
+    pub user_agent: String,
+    pub client_id: String,
+    /// Not used by the native flow.
     pub secret_key: String,
     pub callback_url: String,
-    pub authorize_url: String,
-    pub random_state: String,
+    /// SSO names of the scopes asked for on every login.
+    pub scopes: Vec<String>,
+    pub database_path: String,
+    /// Passphrase of the encrypted database.
+    pub database_key: Option<String>,
+    /// ESI base URL, the public one when missing.
+    pub esi_url: Option<String>,
+    /// SSO base URL, the one of the datasource when missing.
+    pub sso_url: Option<String>,
+    pub datasource: Datasource,
+    /// Seconds an ESI or SSO request may take.
+    pub http_timeout: Option<u64>,
+}
+
+// the secrets stay out of the logs
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hidden = |value: &str| if value.is_empty() { "" } else { "<hidden>" };
+        f.debug_struct("Data")
+            .field("user_agent", &self.user_agent)
+            .field("client_id", &self.client_id)
+            .field("secret_key", &hidden(&self.secret_key))
+            .field("callback_url", &self.callback_url)
+            .field("scopes", &self.scopes)
+            .field("database_path", &self.database_path)
+            .field("database_key", &self.database_key.as_deref().map(hidden))
+            .field("esi_url", &self.esi_url)
+            .field("sso_url", &self.sso_url)
+            .field("datasource", &self.datasource)
+            .field("http_timeout", &self.http_timeout)
+            .finish()
+    }
 }
 
-impl Data{
+impl Data {
     pub fn new() -> Self {
-        Data { 
-            user_agent: String::new(), 
-            client_id: String::new(), 
-            secret_key: String::new(), 
-            callback_url: String::new(),
-            random_state: String::new(),
-            authorize_url: String::new(),
+        Self::default()
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, Error> {
+        toml::from_str(text).map_err(|t_error| Error::Config(t_error.to_string()))
+    }
+
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let text = std::fs::read_to_string(path)?;
+        Data::from_toml(&text)
+    }
+
+    /// Reads the settings from `WEBB_USER_AGENT`, `WEBB_CLIENT_ID`, `WEBB_SECRET_KEY`,
+    /// `WEBB_CALLBACK_URL`, `WEBB_SCOPES` (space separated), `WEBB_DATABASE_PATH`,
+    /// `WEBB_DATABASE_KEY`, `WEBB_ESI_URL`, `WEBB_SSO_URL`, `WEBB_DATASOURCE` and
+    /// `WEBB_HTTP_TIMEOUT`.
+    pub fn from_env() -> Result<Self, Error> {
+        Data::from_vars(std::env::vars())
+    }
+
+    /// Same as [`Data::from_env`] over the given variables.
+    pub fn from_vars<I>(vars: I) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut data = Data::new();
+        for (name, value) in vars {
+            let Some(name) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            match name {
+                "USER_AGENT" => data.user_agent = value,
+                "CLIENT_ID" => data.client_id = value,
+                "SECRET_KEY" => data.secret_key = value,
+                "CALLBACK_URL" => data.callback_url = value,
+                "SCOPES" => data.scopes = value.split_whitespace().map(String::from).collect(),
+                "DATABASE_PATH" => data.database_path = value,
+                "DATABASE_KEY" => data.database_key = Some(value),
+                "ESI_URL" => data.esi_url = Some(value),
+                "SSO_URL" => data.sso_url = Some(value),
+                "DATASOURCE" => data.datasource = value.parse()?,
+                "HTTP_TIMEOUT" => {
+                    let seconds = value.trim().parse().map_err(|_| {
+                        Error::Config(format!("{}HTTP_TIMEOUT is not a number of seconds", ENV_PREFIX))
+                    })?;
+                    data.http_timeout = Some(seconds);
+                }
+                _ => {}
+            }
         }
+        Ok(data)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,35 @@
+use crate::Error;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Key used to encrypt the player database.
+#[derive(Clone)]
+pub enum DatabaseKey {
+    /// Passphrase handed to SQLCipher, which derives the actual key from it.
+    Passphrase(String),
+    /// Callback asked for the passphrase every time a connection is opened,
+    /// so it can come from the OS keyring instead of living in memory.
+    Provider(Arc<dyn Fn() -> Result<String, Error> + Send + Sync>),
+}
+
+impl DatabaseKey {
+    /// Key shared by every install before keys could be supplied,
+    /// only meant to open and convert those databases.
+    pub fn legacy() -> Self {
+        let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, "telescope".as_bytes());
+        DatabaseKey::Passphrase(uuid.to_string())
+    }
+
+    pub fn passphrase(&self) -> Result<String, Error> {
+        match self {
+            DatabaseKey::Passphrase(passphrase) => Ok(passphrase.clone()),
+            DatabaseKey::Provider(provider) => provider(),
+        }
+    }
+
+    // builds `PRAGMA <pragma> = '<passphrase>'` escaping the passphrase
+    pub(crate) fn pragma(&self, pragma: &str) -> Result<String, Error> {
+        let passphrase = self.passphrase()?.replace('\'', "''");
+        Ok(["PRAGMA ", pragma, " = '", passphrase.as_str(), "'"].concat())
+    }
+}
@@ -1,9 +1,22 @@
-use crate::esi::Error;
-use crate::objects::{Alliance, AuthData, BasicCatalog, Character, Corporation};
+use crate::esi::Scope;
+use crate::Error;
+use crate::objects::{Alliance, AuthData, BasicCatalog, Character, Corporation, LoginSession};
 use chrono::{DateTime, Utc};
-use rusqlite::{Connection, ToSql,params};
-use rusqlite::vtab::array;
-use std::rc::Rc;
+use rusqlite::{Connection, OptionalExtension, ToSql, TransactionBehavior, params};
+
+/// Schema version written by this library, databases with a newer one are refused.
+pub const SCHEMA_VERSION: u32 = 5;
+
+// every migration upgrades the schema from the previous version to the one it is keyed with
+type Migration = (u32, fn(&Connection) -> Result<(), Error>);
+
+const MIGRATIONS: &[Migration] = &[
+    (1, PlayerDatabase::migration_auth_per_character),
+    (2, PlayerDatabase::migration_auth_scopes),
+    (3, PlayerDatabase::migration_login_sessions),
+    (4, PlayerDatabase::migration_character_owner),
+    (5, PlayerDatabase::migration_needs_reauth),
+];
 
 pub(crate) struct PlayerDatabase {}
 
@@ -39,8 +52,6 @@ impl PlayerDatabase {
         query = "INSERT INTO metadata (id,value) VALUES (?,?)";
         statement = conn.prepare(query)?;
         statement.execute(["db", "0"])?;
-
-        PlayerDatabase::insert_auth(conn, &AuthData::new())?;
         Ok(true)
     }
 
@@ -53,11 +64,11 @@ impl PlayerDatabase {
 
         let mut result = Vec::new();
         let mut query = String::from(
-            "SELECT id, name, corporation, alliance, portrait, lastLogon, location FROM char",
+            "SELECT id, name, corporation, alliance, portrait, lastLogon, location, owner, transferred, needs_reauth FROM char",
         );
         if !ids.is_empty() {
             let vars = PlayerDatabase::repeat_vars(ids.len());
-            query = format!("SELECT id, name, corporation, alliance, portrait, lastLogon, location FROM char WHERE id IN ({})", vars);
+            query = format!("{} WHERE id IN ({})", query, vars);
         }
         let mut statement = conn.prepare(&query)?;
         let mut rows = statement.query(rusqlite::params_from_iter(ids))?;
@@ -67,13 +78,14 @@ impl PlayerDatabase {
             char.id = row.get(0)?;
             char.name = row.get(1)?;
             char.photo = row.get(4)?;
-            char.corp = if let Ok(value) = row.get::<usize, i32>(2) {
-                Some(PlayerDatabase::select_corporation(conn, vec![value])?[0].clone())
+            // a missing corporation or alliance row leaves the field empty
+            char.corp = if let Some(value) = row.get::<usize, Option<i32>>(2)? {
+                PlayerDatabase::select_corporation(conn, vec![value])?.into_iter().next()
             } else {
                 None
             };
-            char.alliance = if let Ok(value) = row.get::<usize, i32>(3) {
-                Some(PlayerDatabase::select_alliance(conn, vec![value])?[0].clone())
+            char.alliance = if let Some(value) = row.get::<usize, Option<i32>>(3)? {
+                PlayerDatabase::select_alliance(conn, vec![value])?.into_iter().next()
             } else {
                 None
             };
@@ -82,158 +94,349 @@ impl PlayerDatabase {
                 char.last_logon = utc_dt;
             }
             char.location = row.get::<usize, i32>(6)?;
+            char.owner = row.get(7)?;
+            char.transferred = row.get(8)?;
+            char.needs_reauth = row.get(9)?;
+            char.auth = PlayerDatabase::select_auth(conn, char.id)?;
             result.push(char);
         }
         Ok(result)
     }
 
-    // Updated
-    pub(crate) fn update_character(
-        conn: &Connection,
-        character: &Character,
-    ) -> Result<usize, Error> {
+    // inserts the character or updates it when it already exists,
+    // a missing portrait keeps the one already stored
+    pub(crate) fn upsert_character(conn: &Connection, player: &Character) -> Result<usize, Error> {
         #[cfg(feature = "puffin")]
-        puffin::profile_scope!("update_characters");
-        let mut query = String::from("UPDATE char SET name = ?, alliance = ?, corporation = ?, ");
-        query += "lastlogon = ?, location = ? WHERE id = ?;";
-        let mut statement = conn.prepare(query.as_str()).unwrap();
+        puffin::profile_scope!("upsert_character");
+
+        let mut query = String::from("INSERT INTO char (id,");
+        query += "name,corporation,alliance,portrait,lastLogon,location,owner,transferred,";
+        query += "needs_reauth) VALUES (?,?,?,?,?,?,?,?,?,?)";
+        query += " ON CONFLICT(id) DO UPDATE SET name = excluded.name,";
+        query += " corporation = excluded.corporation, alliance = excluded.alliance,";
+        query += " portrait = COALESCE(excluded.portrait, portrait),";
+        query += " lastLogon = excluded.lastLogon, location = excluded.location,";
+        query += " owner = COALESCE(excluded.owner, owner), transferred = excluded.transferred,";
+        query += " needs_reauth = excluded.needs_reauth";
+        let mut statement = conn.prepare(query.as_str())?;
         let params = rusqlite::params![
-            character.name,
-            character.alliance.as_ref().unwrap().id,
-            character.corp.as_ref().unwrap().id,
-            character.last_logon.to_string(),
-            character.location,
-            character.id
+            player.id,
+            player.name,
+            player.corp.as_ref().map(|corp| corp.id),
+            player.alliance.as_ref().map(|alliance| alliance.id),
+            player.photo,
+            player.last_logon.to_rfc3339(),
+            player.location,
+            player.owner,
+            player.transferred,
+            player.needs_reauth
         ];
-        let rows: usize = statement.execute(params)?;
-        //PlayerDatabase::update_auth(conn, character.id, character.auth.as_ref().unwrap())?;
+        let rows = statement.execute(params)?;
+        if let Some(auth) = &player.auth {
+            PlayerDatabase::upsert_auth(conn, player.id, auth)?;
+        }
         Ok(rows)
     }
 
-    pub(crate) fn select_auth(conn: &Connection) -> Result<AuthData, Error> {
-        let values = vec![String::from("token"),String::from("expiration"),String::from("refresh_token")];
-        let mut result = AuthData::new();
-        let query = String::from(
-            "SELECT id, value FROM metadata WHERE id IN rarray(?1)",
-        );
-
-        let mut statement = conn.prepare(&query)?;
-        let id_list: array::Array = Rc::new(
-            values
-                .into_iter()
-                .map(rusqlite::types::Value::from)
-                .collect::<Vec<rusqlite::types::Value>>(),
-        );
-        let mut rows = statement.query([id_list])?;
-        while let Some(row) = rows.next()? {
-            let field:String = row.get(0)?;
-            if field.as_str() == "token" {
-                result.token =  row.get(1)?;
-            }
-            if field.as_str() == "expiration" {
-                let date_as_string = row.get::<usize,String>(1)?;
-                
-                if let Ok(utc_dt) = DateTime::parse_from_rfc3339(&date_as_string){
-                    result.expiration =  Some(utc_dt.to_utc());
-                }
+    // stores the characters with their corporations and alliances in one transaction
+    pub(crate) fn write_characters(conn: &mut Connection, chars: &[Character]) -> Result<usize, Error> {
+        let transaction = conn.transaction()?;
+        let mut rows = 0;
+        for char in chars {
+            // first we need to assure that Corporation and alliance exists on database
+            if let Some(corp) = &char.corp {
+                PlayerDatabase::upsert_corporation(&transaction, corp)?;
             }
-            if field.as_str() == "refresh_token" {
-                result.refresh_token =  row.get(1)?;
+            if let Some(alliance) = &char.alliance {
+                PlayerDatabase::upsert_alliance(&transaction, alliance)?;
             }
+            rows += PlayerDatabase::upsert_character(&transaction, char)?;
         }
-        Ok(result)
+        transaction.commit()?;
+        Ok(rows)
     }
 
-    pub(crate) fn insert_auth(conn: &Connection, auth_data:&AuthData) -> Result<usize, Error> {
-        let mut data: Vec<(String,String)> = Vec::new();
-        let mut query = String::from("INSERT INTO metadata (id,value)");
-        query += " VALUES (?1,?2)";
-        data.push((String::from("token"),auth_data.token.clone()));
-        data.push((String::from("refresh_token"),auth_data.refresh_token.clone()));
-        if let Some(expiration_date) = auth_data.expiration {
-            data.push((String::from("expiration"),expiration_date.to_rfc3339()));
+    pub(crate) fn select_auth(conn: &Connection, id: i32) -> Result<Option<AuthData>, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("select_auth");
+
+        let query = "SELECT token, refresh_token, expiration, scopes FROM auth WHERE id = ?1";
+        let mut statement = conn.prepare(query)?;
+        let mut rows = statement.query([id])?;
+        if let Some(row) = rows.next()? {
+            let mut result = AuthData::new();
+            result.token = row.get(0)?;
+            result.refresh_token = row.get(1)?;
+            if let Some(date_as_string) = row.get::<usize, Option<String>>(2)? {
+                if let Ok(utc_dt) = DateTime::parse_from_rfc3339(&date_as_string) {
+                    result.expiration = Some(utc_dt.to_utc());
+                }
+            }
+            result.scopes = Scope::split(&row.get::<usize, String>(3)?);
+            Ok(Some(result))
         } else {
-            data.push((String::from("expiration"),String::new()));
-        }
-        
-        let mut rows = 0;
-        for item in data {
-            let mut statement = conn.prepare(&query)?;
-            let affected_rows = statement.execute(params![item.0,item.1])?;
-            rows += affected_rows;
+            Ok(None)
         }
+    }
+
+    pub(crate) fn upsert_auth(conn: &Connection, id: i32, auth_data: &AuthData) -> Result<usize, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("upsert_auth");
+
+        let mut query =
+            String::from("INSERT INTO auth (id, token, refresh_token, expiration, scopes)");
+        query += " VALUES (?1,?2,?3,?4,?5) ON CONFLICT(id) DO UPDATE SET token = excluded.token,";
+        query += " refresh_token = excluded.refresh_token, expiration = excluded.expiration,";
+        query += " scopes = excluded.scopes";
+        let mut statement = conn.prepare(&query)?;
+        let expiration = auth_data.expiration.map(|date| date.to_rfc3339());
+        let rows = statement.execute(params![
+            id,
+            auth_data.token,
+            auth_data.refresh_token,
+            expiration,
+            Scope::join(&auth_data.scopes)
+        ])?;
         Ok(rows)
     }
 
-    pub(crate) fn update_auth(conn: &Connection, auth_data:&AuthData) -> Result<usize, Error> {
-        let query = String::from("UPDATE metadata SET value = ?1 WHERE id = ?2;");
-        let mut data:Vec<(String,String)> = Vec::new();
-        data.push((String::from("token"),auth_data.token.clone()));
-        data.push((String::from("refresh_token"),auth_data.refresh_token.clone()));
-        if let Some(expiration_date) = auth_data.expiration {
-            data.push((String::from("expiration"),expiration_date.to_rfc3339()));
-        } else {
-            data.push((String::from("expiration"),String::new()));
+    pub(crate) fn delete_auth(conn: &Connection, ids: Vec<i32>) -> Result<usize, Error> {
+        PlayerDatabase::delete_general(conn, "auth", ids)
+    }
+
+    fn repeat_vars(count: usize) -> String {
+        assert_ne!(count, 0);
+        let mut s = "?,".repeat(count);
+        // Remove trailing comma
+        s.pop();
+        s
+    }
+
+    pub(crate) fn schema_version(conn: &Connection) -> Result<u32, Error> {
+        let query = "SELECT value FROM metadata WHERE id = 'db'";
+        let value = conn
+            .query_row(query, [], |row| row.get::<usize, String>(0))
+            .optional()?;
+        match value.map(|version| version.parse::<u32>()) {
+            Some(Ok(version)) => Ok(version),
+            // databases created before versioning have no usable value
+            _ => Ok(0),
         }
-        let mut rows = 0;
-        for item in data {
-            let mut statement = conn.prepare(&query).unwrap();
-            let affected_rows = statement.execute(params![item.1,item.0])?;
-            statement.finalize()?;
-            rows += affected_rows;
+    }
+
+    /// Upgrades the schema to [`SCHEMA_VERSION`] inside a single transaction,
+    /// returning the version the database had before.
+    pub(crate) fn migrate_database(conn: &mut Connection) -> Result<u32, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("migrate_database");
+
+        // the write lock is taken before reading the version, so another process
+        // opening the same database waits and then finds it current
+        let transaction = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let found = PlayerDatabase::schema_version(&transaction)?;
+        if found > SCHEMA_VERSION {
+            return Err(Error::SchemaVersion {
+                found,
+                supported: SCHEMA_VERSION,
+            });
         }
-        Ok(rows)
+        if found == SCHEMA_VERSION {
+            return Ok(found);
+        }
+
+        for (version, migration) in MIGRATIONS.iter().filter(|(version, _)| *version > found) {
+            migration(&transaction)?;
+            let query = "INSERT OR REPLACE INTO metadata (id,value) VALUES ('db',?1)";
+            transaction.execute(query, [version.to_string()])?;
+        }
+        transaction.commit()?;
+        Ok(found)
     }
 
-    pub(crate) fn delete_auth(conn: &Connection) -> Result<usize, Error> {
-        let query = "DELETE FROM metadata WHERE id IN ('token','expiration','refresh_token');";
-        let mut statement = conn.prepare(query).unwrap();
-        let affected_rows = statement.execute([])?;
-        Ok(affected_rows)
+    fn table_exists(conn: &Connection, table: &str) -> Result<bool, Error> {
+        let query = "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1";
+        let count: i32 = conn.query_row(query, [table], |row| row.get(0))?;
+        Ok(count > 0)
     }
 
-    pub(crate) fn insert_character(conn: &Connection, player: &Character) -> Result<usize, Error> {
-        #[cfg(feature = "puffin")]
-        puffin::profile_scope!("insert_character");
+    // Schema migrations
 
-        let mut query = String::from("INSERT INTO char (id,");
-        query += "name,corporation,alliance,portrait,lastLogon,location) VALUES (?,?,?,?,?,?,?)";
-        let mut statement = conn.prepare(query.as_str())?;
-        let dt = player.last_logon.to_rfc3339();
-        statement.raw_bind_parameter(1, player.id)?;
-        statement.raw_bind_parameter(2, &player.name)?;
-        if player.corp.is_some() {
-            statement.raw_bind_parameter(3, player.corp.as_ref().unwrap().id)?;
+    // v1: tokens move from the single metadata entry to a table keyed by character
+    fn migration_auth_per_character(conn: &Connection) -> Result<(), Error> {
+        let mut query = String::from("CREATE TABLE auth (id INTEGER PRIMARY KEY REFERENCES char(id)");
+        query += " ON DELETE CASCADE ON UPDATE CASCADE, token TEXT NOT NULL,";
+        query += " refresh_token TEXT NOT NULL, expiration DATETIME)";
+        conn.execute(&query, [])?;
+
+        // the metadata token can only belong to the character when there is just one
+        let legacy = ["token", "refresh_token", "expiration"];
+        let mut auth = AuthData::new();
+        let mut statement = conn.prepare("SELECT id, value FROM metadata WHERE id IN (?1,?2,?3)")?;
+        let mut rows = statement.query(legacy)?;
+        while let Some(row) = rows.next()? {
+            let value: String = row.get(1)?;
+            match row.get::<usize, String>(0)?.as_str() {
+                "token" => auth.token = value,
+                "refresh_token" => auth.refresh_token = value,
+                _ => {
+                    if let Ok(utc_dt) = DateTime::parse_from_rfc3339(&value) {
+                        auth.expiration = Some(utc_dt.to_utc());
+                    }
+                }
+            }
         }
-        if player.alliance.is_some() {
-            statement.raw_bind_parameter(4, player.alliance.as_ref().unwrap().id)?;
+        let chars: Vec<i32> = conn
+            .prepare("SELECT id FROM char")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        if chars.len() == 1 && !auth.refresh_token.is_empty() {
+            // written by hand, later migrations add columns upsert_auth expects
+            let query = "INSERT INTO auth (id, token, refresh_token, expiration) VALUES (?1,?2,?3,?4)";
+            let expiration = auth.expiration.map(|date| date.to_rfc3339());
+            conn.execute(query, params![chars[0], auth.token, auth.refresh_token, expiration])?;
         }
-        if player.photo.is_some() {
-            statement.raw_bind_parameter(5, player.photo.clone().unwrap())?;
+        conn.execute("DELETE FROM metadata WHERE id IN (?1,?2,?3)", legacy)?;
+
+        // early databases kept access tokens without refresh token, they can't be used anymore
+        if PlayerDatabase::table_exists(conn, "char_auth")? {
+            conn.execute("DROP TABLE char_auth", [])?;
         }
-        statement.raw_bind_parameter(6, dt)?;
-        statement.raw_bind_parameter(7, player.location)?;
-        let rows = statement.raw_execute()?;
-        //PlayerDatabase::insert_auth(conn,player.id,player.auth.as_ref().unwrap())?;
-        Ok(rows)
+        Ok(())
     }
 
-    fn repeat_vars(count: usize) -> String {
-        assert_ne!(count, 0);
-        let mut s = "?,".repeat(count);
-        // Remove trailing comma
-        s.pop();
-        s
+    // v2: scopes granted along with each token
+    fn migration_auth_scopes(conn: &Connection) -> Result<(), Error> {
+        conn.execute("ALTER TABLE auth ADD COLUMN scopes TEXT NOT NULL DEFAULT ''", [])?;
+        Ok(())
     }
 
-    pub(crate) fn migrate_database() -> Result<bool, Error> {
-        Ok(true)
+    // v3: logins in progress survive a restart
+    fn migration_login_sessions(conn: &Connection) -> Result<(), Error> {
+        let mut query = String::from("CREATE TABLE login_session (state TEXT PRIMARY KEY,");
+        query += " verifier TEXT, character INTEGER, scopes TEXT NOT NULL,";
+        query += " created_at DATETIME NOT NULL)";
+        conn.execute(&query, [])?;
+        Ok(())
+    }
+
+    // v4: account owning each character, to notice when it is transferred
+    fn migration_character_owner(conn: &Connection) -> Result<(), Error> {
+        conn.execute("ALTER TABLE char ADD COLUMN owner TEXT", [])?;
+        conn.execute("ALTER TABLE char ADD COLUMN transferred INTEGER NOT NULL DEFAULT 0", [])?;
+        Ok(())
+    }
+
+    // v5: characters whose tokens the SSO refused for good
+    fn migration_needs_reauth(conn: &Connection) -> Result<(), Error> {
+        conn.execute("ALTER TABLE char ADD COLUMN needs_reauth INTEGER NOT NULL DEFAULT 0", [])?;
+        Ok(())
+    }
+
+    pub(crate) fn set_needs_reauth(conn: &Connection, id: i32, needs_reauth: bool) -> Result<usize, Error> {
+        let query = "UPDATE char SET needs_reauth = ?1 WHERE id = ?2";
+        Ok(conn.execute(query, params![needs_reauth, id])?)
+    }
+
+    pub(crate) fn update_owner(conn: &Connection, id: i32, owner: &str) -> Result<usize, Error> {
+        let query = "UPDATE char SET owner = ?1 WHERE id = ?2";
+        Ok(conn.execute(query, params![owner, id])?)
+    }
+
+    pub(crate) fn set_transferred(conn: &Connection, id: i32, transferred: bool) -> Result<usize, Error> {
+        let query = "UPDATE char SET transferred = ?1 WHERE id = ?2";
+        Ok(conn.execute(query, params![transferred, id])?)
     }
 
     pub(crate) fn delete_characters(conn: &Connection, ids: Vec<i32>) -> Result<usize, Error> {
+        PlayerDatabase::delete_auth(conn, ids.clone())?;
         PlayerDatabase::delete_general(conn, "char", ids)
     }
 
+    // Login sessions
+    pub(crate) fn insert_login_session(conn: &Connection, session: &LoginSession) -> Result<usize, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("insert_login_session");
+
+        let mut query = String::from("INSERT INTO login_session");
+        query += " (state, verifier, character, scopes, created_at) VALUES (?1,?2,?3,?4,?5)";
+        let rows = conn.execute(
+            &query,
+            params![
+                session.state,
+                session.verifier,
+                session.character_id,
+                Scope::join(&session.scopes),
+                session.created_at.to_rfc3339()
+            ],
+        )?;
+        Ok(rows)
+    }
+
+    pub(crate) fn select_login_sessions(conn: &Connection) -> Result<Vec<LoginSession>, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("select_login_sessions");
+
+        let query = "SELECT state, verifier, character, scopes, created_at FROM login_session";
+        let mut statement = conn.prepare(query)?;
+        let mut rows = statement.query([])?;
+        let mut result = Vec::new();
+        while let Some(row) = rows.next()? {
+            result.push(PlayerDatabase::login_session_from_row(row)?);
+        }
+        Ok(result)
+    }
+
+    // sessions are single use, reading one deletes it
+    pub(crate) fn take_login_session(conn: &mut Connection, state: &str) -> Result<Option<LoginSession>, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("take_login_session");
+
+        let transaction = conn.transaction()?;
+        let query = "SELECT state, verifier, character, scopes, created_at FROM login_session WHERE state = ?1";
+        let session = {
+            let mut statement = transaction.prepare(query)?;
+            let mut rows = statement.query([state])?;
+            match rows.next()? {
+                Some(row) => Some(PlayerDatabase::login_session_from_row(row)?),
+                None => None,
+            }
+        };
+        transaction.execute("DELETE FROM login_session WHERE state = ?1", [state])?;
+        transaction.commit()?;
+        Ok(session)
+    }
+
+    pub(crate) fn delete_login_sessions(conn: &Connection, states: Vec<String>) -> Result<usize, Error> {
+        if states.is_empty() {
+            return Ok(0);
+        }
+        let vars = PlayerDatabase::repeat_vars(states.len());
+        let query = format!("DELETE FROM login_session WHERE state IN ({})", vars);
+        Ok(conn.execute(&query, rusqlite::params_from_iter(states))?)
+    }
+
+    // RFC 3339 dates in UTC sort the same as text
+    pub(crate) fn delete_login_sessions_before(conn: &Connection, before: DateTime<Utc>) -> Result<usize, Error> {
+        let rows = conn.execute(
+            "DELETE FROM login_session WHERE created_at < ?1",
+            [before.to_rfc3339()],
+        )?;
+        Ok(rows)
+    }
+
+    fn login_session_from_row(row: &rusqlite::Row) -> Result<LoginSession, Error> {
+        let created_at = row.get::<usize, String>(4)?;
+        Ok(LoginSession {
+            state: row.get(0)?,
+            verifier: row.get(1)?,
+            character_id: row.get(2)?,
+            scopes: Scope::split(&row.get::<usize, String>(3)?),
+            created_at: DateTime::parse_from_rfc3339(&created_at)
+                .map(|date| date.to_utc())
+                .unwrap_or_default(),
+        })
+    }
+
     // Corporation
     pub(crate) fn select_corporation(
         conn: &Connection,
@@ -260,18 +463,8 @@ impl PlayerDatabase {
         Ok(result)
     }
 
-    pub(crate) fn update_corporation(
-        conn: &Connection,
-        corp: &Corporation,
-    ) -> Result<usize, Error> {
-        PlayerDatabase::update_catalog(conn, "corp", corp)
-    }
-
-    pub(crate) fn insert_corporation(
-        conn: &Connection,
-        corp: &Corporation,
-    ) -> Result<usize, Error> {
-        PlayerDatabase::insert_catalog(conn, "corp", corp)
+    pub(crate) fn upsert_corporation(conn: &Connection, corp: &Corporation) -> Result<usize, Error> {
+        PlayerDatabase::upsert_catalog(conn, "corp", corp)
     }
 
     pub(crate) fn delete_corporation(conn: &Connection, ids: Vec<i32>) -> Result<usize, Error> {
@@ -304,13 +497,10 @@ impl PlayerDatabase {
         Ok(result)
     }
 
-    pub(crate) fn update_alliance(conn: &Connection, ally: &Alliance) -> Result<usize, Error> {
-        PlayerDatabase::update_catalog(conn, "alliance", ally)
+    pub(crate) fn upsert_alliance(conn: &Connection, ally: &Alliance) -> Result<usize, Error> {
+        PlayerDatabase::upsert_catalog(conn, "alliance", ally)
     }
 
-    pub(crate) fn insert_alliance(conn: &Connection, ally: &Alliance) -> Result<usize, Error> {
-        PlayerDatabase::insert_catalog(conn, "alliance", ally)
-    }
     pub(crate) fn delete_alliance(conn: &Connection, ids: Vec<i32>) -> Result<usize, Error> {
         PlayerDatabase::delete_general(conn, "alliance", ids)
     }
@@ -324,18 +514,14 @@ impl PlayerDatabase {
             let vars = PlayerDatabase::repeat_vars(ids.len());
             let query = format!("DELETE FROM {} WHERE id IN ({})", table, vars);
             let mut statement = conn.prepare(&query)?;
-            if let Ok(rows) = statement.execute(rusqlite::params_from_iter(ids)) {
-                Ok(rows)
-            } else {
-                Ok(0)
-            }
+            Ok(statement.execute(rusqlite::params_from_iter(ids))?)
         } else {
             Ok(0)
         }
     }
 
-    // generic Function to insert new values on a catalog
-    fn insert_catalog<B: BasicCatalog>(
+    // generic Function to insert or update values on a catalog
+    fn upsert_catalog<B: BasicCatalog>(
         conn: &Connection,
         table: &str,
         obj: &B,
@@ -344,31 +530,15 @@ impl PlayerDatabase {
         <B as BasicCatalog>::Output: ToSql,
     {
         #[cfg(feature = "puffin")]
-        puffin::profile_scope!("insert_catalog");
+        puffin::profile_scope!("upsert_catalog");
 
-        let query = format!("INSERT INTO {} (id,name) VALUES (?,?);", table);
+        let query = format!(
+            "INSERT INTO {} (id,name) VALUES (?,?) ON CONFLICT(id) DO UPDATE SET name = excluded.name;",
+            table
+        );
         let mut statement = conn.prepare(&query)?;
         let params = rusqlite::params![obj.id(), obj.name()];
         let rows = statement.execute(params)?;
         Ok(rows)
     }
-
-    // generic Function to update values on a catalog
-    fn update_catalog<B: BasicCatalog>(
-        conn: &Connection,
-        table: &str,
-        obj: &B,
-    ) -> Result<usize, Error>
-    where
-        <B as BasicCatalog>::Output: ToSql,
-    {
-        #[cfg(feature = "puffin")]
-        puffin::profile_scope!("update_catalog");
-
-        let query = format!("UPDATE {} SET name = ? WHERE id = ?;", table);
-        let mut statement = conn.prepare(&query)?;
-        let params = rusqlite::params![obj.name(), obj.id()];
-        let rows = statement.execute(params)?;
-        Ok(rows)
-    }
 }
@@ -0,0 +1,154 @@
+use super::EsiManager;
+use crate::Error;
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Events not read yet are dropped once there are this many.
+const EVENT_BUFFER: usize = 32;
+
+/// What the background refresher reports.
+#[derive(Debug)]
+pub enum RefreshEvent {
+    /// The character got a new token, already stored.
+    Refreshed {
+        character_id: i32,
+        expiration: Option<DateTime<Utc>>,
+    },
+    /// The character now belongs to another account, its tokens were dropped and
+    /// it needs to log in again.
+    Transferred { character_id: i32 },
+    /// The SSO refused the tokens for good, the character is left alone until
+    /// it logs in again.
+    ReauthRequired { character_id: i32 },
+    /// The refresh failed, it is tried again after [`RefreshOptions::retry_delay`].
+    Failed { character_id: i32, error: Error },
+}
+
+/// How the background refresher behaves.
+#[derive(Clone)]
+pub struct RefreshOptions {
+    /// Time before the expiration a token is refreshed.
+    pub margin: Duration,
+    /// Longest the task sleeps before looking at the characters again,
+    /// so logins made in the meantime are picked up.
+    pub poll_interval: Duration,
+    /// Shortest time between two refreshes of the same character.
+    pub retry_delay: Duration,
+    /// Current time, tests replace it to move the time forward.
+    pub clock: Arc<dyn Fn() -> DateTime<Utc> + Send + Sync>,
+}
+
+impl Default for RefreshOptions {
+    fn default() -> Self {
+        RefreshOptions {
+            margin: Duration::from_secs(60),
+            poll_interval: Duration::from_secs(30),
+            retry_delay: Duration::from_secs(60),
+            clock: Arc::new(Utc::now),
+        }
+    }
+}
+
+/// Handle of the task started by [`EsiManager::start_refresher`], dropping it
+/// stops the task too.
+pub struct RefreshHandle {
+    pub events: mpsc::Receiver<RefreshEvent>,
+    stop: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl RefreshHandle {
+    /// Stops the task and waits for it, a refresh in flight is let finish
+    /// so the rotated token isn't lost.
+    pub async fn stop(mut self) -> Result<(), Error> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        (&mut self.task).await?;
+        Ok(())
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+}
+
+impl EsiManager {
+    /// Starts a task that refreshes the tokens of the characters shortly before
+    /// they expire and stores them, on a clone of the manager.
+    pub fn start_refresher(&self, options: RefreshOptions) -> RefreshHandle {
+        let (tx, events) = mpsc::channel(EVENT_BUFFER);
+        let (stop, stop_rx) = oneshot::channel();
+        let task = tokio::spawn(run(self.clone(), options, tx, stop_rx));
+        RefreshHandle {
+            events,
+            stop: Some(stop),
+            task,
+        }
+    }
+}
+
+async fn run(
+    mut mon: EsiManager,
+    options: RefreshOptions,
+    events: mpsc::Sender<RefreshEvent>,
+    mut stop: oneshot::Receiver<()>,
+) {
+    let margin = TimeDelta::from_std(options.margin).unwrap_or_default();
+    let retry_delay = TimeDelta::from_std(options.retry_delay).unwrap_or_default();
+    let poll_interval = TimeDelta::from_std(options.poll_interval).unwrap_or_default();
+    let mut last_attempt: HashMap<i32, DateTime<Utc>> = HashMap::new();
+
+    loop {
+        let now = (options.clock)();
+        let mut wake = now + poll_interval;
+        for character in mon.characters.list() {
+            let Some(auth) = character.auth.filter(|_| !character.needs_reauth) else {
+                continue;
+            };
+            let mut due = auth.expiration.map_or(now, |expiration| expiration - margin);
+            if let Some(attempt) = last_attempt.get(&character.id) {
+                due = due.max(*attempt + retry_delay);
+            }
+            if due > now {
+                wake = wake.min(due);
+                continue;
+            }
+
+            last_attempt.insert(character.id, now);
+            let event = match mon.refresh_expiring(character.id, now + margin).await {
+                Ok(false) => None,
+                Ok(true) => Some(RefreshEvent::Refreshed {
+                    character_id: character.id,
+                    expiration: mon.characters.auth(character.id).and_then(|auth| auth.expiration),
+                }),
+                Err(Error::CharacterTransferred(character_id)) => {
+                    Some(RefreshEvent::Transferred { character_id })
+                }
+                Err(Error::ReauthRequired(character_id)) => {
+                    Some(RefreshEvent::ReauthRequired { character_id })
+                }
+                Err(error) => Some(RefreshEvent::Failed {
+                    character_id: character.id,
+                    error,
+                }),
+            };
+            if let Some(event) = event {
+                // nobody has to listen
+                let _ = events.try_send(event);
+            }
+            wake = wake.min(now + retry_delay);
+        }
+
+        let delay = (wake - (options.clock)()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            // asked to stop or the handle is gone
+            _ = &mut stop => break,
+        }
+    }
+}
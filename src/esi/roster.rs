@@ -0,0 +1,100 @@
+use crate::objects::{AuthData, Character};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+/// Characters known by the [`super::EsiManager`] along with their tokens.
+///
+/// Every clone of the manager shares the same roster, so a token refreshed
+/// by one of them is the one the others use next.
+#[derive(Clone, Default)]
+pub struct Roster {
+    characters: Arc<RwLock<Vec<Character>>>,
+    // one lock per character so a rotating refresh token is only spent once
+    refresh_locks: Arc<Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+// only the ids, the characters carry their tokens
+impl fmt::Debug for Roster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<i32> = self.list().iter().map(|char| char.id).collect();
+        f.debug_struct("Roster").field("characters", &ids).finish_non_exhaustive()
+    }
+}
+
+impl Roster {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy of the characters as they are right now.
+    pub fn list(&self) -> Vec<Character> {
+        let characters = self.characters.read().unwrap_or_else(PoisonError::into_inner);
+        characters.clone()
+    }
+
+    pub fn get(&self, character_id: i32) -> Option<Character> {
+        let characters = self.characters.read().unwrap_or_else(PoisonError::into_inner);
+        characters.iter().find(|char| char.id == character_id).cloned()
+    }
+
+    pub fn auth(&self, character_id: i32) -> Option<AuthData> {
+        let characters = self.characters.read().unwrap_or_else(PoisonError::into_inner);
+        characters
+            .iter()
+            .find(|char| char.id == character_id)
+            .and_then(|char| char.auth.clone())
+    }
+
+    /// Adds the character or replaces the one with the same id.
+    pub fn insert(&self, character: Character) {
+        let mut characters = self.characters.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(char) = characters.iter_mut().find(|char| char.id == character.id) {
+            *char = character;
+        } else {
+            characters.push(character);
+        }
+    }
+
+    pub fn remove(&self, character_id: i32) -> Option<Character> {
+        let mut characters = self.characters.write().unwrap_or_else(PoisonError::into_inner);
+        let index = characters.iter().position(|char| char.id == character_id)?;
+        Some(characters.remove(index))
+    }
+
+    pub fn len(&self) -> usize {
+        let characters = self.characters.read().unwrap_or_else(PoisonError::into_inner);
+        characters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn replace_all(&self, list: Vec<Character>) {
+        let mut characters = self.characters.write().unwrap_or_else(PoisonError::into_inner);
+        *characters = list;
+    }
+
+    pub(crate) fn set_auth(&self, character_id: i32, auth: Option<AuthData>) {
+        let mut characters = self.characters.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(char) = characters.iter_mut().find(|char| char.id == character_id) {
+            char.auth = auth;
+        }
+    }
+
+    pub(crate) fn update(&self, character_id: i32, change: impl FnOnce(&mut Character)) {
+        let mut characters = self.characters.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(char) = characters.iter_mut().find(|char| char.id == character_id) {
+            change(char);
+        }
+    }
+
+    pub(crate) fn refresh_lock(&self, character_id: i32) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self
+            .refresh_locks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        Arc::clone(locks.entry(character_id).or_default())
+    }
+}
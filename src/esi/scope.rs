@@ -0,0 +1,112 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// ESI scope an application can ask a character for.
+///
+/// Scopes without a variant of their own are kept in [`Scope::Other`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Scope {
+    PublicData,
+    ReadLocation,
+    ReadShipType,
+    ReadOnline,
+    ReadSkills,
+    ReadSkillqueue,
+    ReadWallet,
+    ReadAssets,
+    ReadClones,
+    ReadImplants,
+    ReadContacts,
+    ReadStandings,
+    ReadMail,
+    SendMail,
+    ReadFittings,
+    WriteFittings,
+    ReadFleet,
+    WriteFleet,
+    WriteWaypoint,
+    OpenWindow,
+    SearchStructures,
+    ReadStructures,
+    ReadNotifications,
+    ReadCorporationMembership,
+    Other(String),
+}
+
+const NAMES: &[(Scope, &str)] = &[
+    (Scope::PublicData, "publicData"),
+    (Scope::ReadLocation, "esi-location.read_location.v1"),
+    (Scope::ReadShipType, "esi-location.read_ship_type.v1"),
+    (Scope::ReadOnline, "esi-location.read_online.v1"),
+    (Scope::ReadSkills, "esi-skills.read_skills.v1"),
+    (Scope::ReadSkillqueue, "esi-skills.read_skillqueue.v1"),
+    (Scope::ReadWallet, "esi-wallet.read_character_wallet.v1"),
+    (Scope::ReadAssets, "esi-assets.read_assets.v1"),
+    (Scope::ReadClones, "esi-clones.read_clones.v1"),
+    (Scope::ReadImplants, "esi-clones.read_implants.v1"),
+    (Scope::ReadContacts, "esi-characters.read_contacts.v1"),
+    (Scope::ReadStandings, "esi-characters.read_standings.v1"),
+    (Scope::ReadMail, "esi-mail.read_mail.v1"),
+    (Scope::SendMail, "esi-mail.send_mail.v1"),
+    (Scope::ReadFittings, "esi-fittings.read_fittings.v1"),
+    (Scope::WriteFittings, "esi-fittings.write_fittings.v1"),
+    (Scope::ReadFleet, "esi-fleets.read_fleet.v1"),
+    (Scope::WriteFleet, "esi-fleets.write_fleet.v1"),
+    (Scope::WriteWaypoint, "esi-ui.write_waypoint.v1"),
+    (Scope::OpenWindow, "esi-ui.open_window.v1"),
+    (Scope::SearchStructures, "esi-search.search_structures.v1"),
+    (Scope::ReadStructures, "esi-universe.read_structures.v1"),
+    (Scope::ReadNotifications, "esi-characters.read_notifications.v1"),
+    (
+        Scope::ReadCorporationMembership,
+        "esi-corporations.read_corporation_membership.v1",
+    ),
+];
+
+impl Scope {
+    /// Name of the scope as the SSO knows it.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Scope::Other(name) => name.as_str(),
+            scope => NAMES
+                .iter()
+                .find(|(known, _)| known == scope)
+                .map(|(_, name)| *name)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Joins the scopes the way the SSO expects them.
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Splits a space separated list of scopes.
+    pub fn split(scopes: &str) -> Vec<Scope> {
+        scopes.split_whitespace().map(Scope::from).collect()
+    }
+}
+
+impl From<&str> for Scope {
+    fn from(value: &str) -> Self {
+        NAMES
+            .iter()
+            .find(|(_, name)| *name == value)
+            .map(|(scope, _)| scope.clone())
+            .unwrap_or_else(|| Scope::Other(value.to_string()))
+    }
+}
+
+impl FromStr for Scope {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Scope::from(value))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
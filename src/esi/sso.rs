@@ -0,0 +1,285 @@
+use super::Scope;
+use crate::objects::AuthData;
+use crate::Error;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Method, Request};
+use hyper_tls::HttpsConnector;
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+/// Base URL of the EVE Online SSO.
+pub const SSO_URL: &str = "https://login.eveonline.com/";
+
+/// Base URL of the SSO of the Singularity test server.
+pub const SISI_SSO_URL: &str = "https://sisilogin.testeveonline.com/";
+
+/// Longest an SSO request may take unless [`Sso::timeout`] says otherwise.
+pub const SSO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Claims of an access token that passed [`Sso::validate_token`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenClaims {
+    pub character_id: i32,
+    pub name: String,
+    /// Hash of the account owning the character, it changes when the character is transferred.
+    pub owner: String,
+    /// Scopes granted to the application.
+    pub scopes: Vec<Scope>,
+    pub expiration: DateTime<Utc>,
+    pub jti: String,
+}
+
+// claims as the SSO writes them
+#[derive(Deserialize)]
+struct RawClaims {
+    sub: String,
+    name: String,
+    owner: String,
+    // a single scope comes as a string instead of a list
+    #[serde(default)]
+    scp: Option<Value>,
+    exp: i64,
+    #[serde(default)]
+    jti: String,
+}
+
+// answer of the token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+    refresh_token: String,
+}
+
+// body of a refused OAuth request
+#[derive(Deserialize)]
+struct OAuthError {
+    error: String,
+    #[serde(default)]
+    error_description: Option<String>,
+}
+
+/// Calls to the SSO that the ESI client doesn't cover.
+#[derive(Clone)]
+pub struct Sso {
+    /// Base URL the SSO endpoints hang from, ending with `/`.
+    pub base_url: String,
+    /// Longest a request may take, refreshes run holding the lock of the character.
+    pub timeout: Duration,
+    client_id: String,
+    client_secret: Option<String>,
+    // signing keys, shared by clones and fetched again when the SSO rotates them
+    jwks: Arc<Mutex<Option<JwkSet>>>,
+}
+
+impl Sso {
+    pub fn new(client_id: &str, _client_secret: &str) -> Self {
+        // the native flow has no secret, the client only identifies itself
+        #[cfg(not(feature = "native-auth-flow"))]
+        let client_secret = Some(_client_secret.to_string());
+        #[cfg(feature = "native-auth-flow")]
+        let client_secret = None;
+
+        Sso {
+            base_url: SSO_URL.to_string(),
+            timeout: SSO_TIMEOUT,
+            client_id: client_id.to_string(),
+            client_secret,
+            jwks: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn token_url(&self) -> String {
+        format!("{}v2/oauth/token", self.base_url)
+    }
+
+    pub fn revoke_url(&self) -> String {
+        format!("{}v2/oauth/revoke", self.base_url)
+    }
+
+    pub fn jwks_url(&self) -> String {
+        format!("{}oauth/jwks", self.base_url)
+    }
+
+    /// Revokes the refresh token, along with every access token issued from it.
+    pub async fn revoke(&self, refresh_token: &str) -> Result<(), Error> {
+        let form = [("token_type_hint", "refresh_token"), ("token", refresh_token)];
+        let request = self.form_request(self.revoke_url(), &form)?;
+        self.send(request).await?;
+        Ok(())
+    }
+
+    /// Exchanges the refresh token for new tokens, the scopes are left empty.
+    ///
+    /// A refusal comes back as [`Error::Sso`] with the OAuth error, so a revoked
+    /// token (`invalid_grant`) can be told apart from a network failure.
+    pub async fn refresh(&self, refresh_token: &str) -> Result<AuthData, Error> {
+        let form = [("grant_type", "refresh_token"), ("refresh_token", refresh_token)];
+        let request = self.form_request(self.token_url(), &form)?;
+        let body = self.send(request).await?;
+        let tokens: TokenResponse = serde_json::from_slice(&body).map_err(|t_error| {
+            Error::InvalidToken(format!("unreadable token answer: {}", t_error))
+        })?;
+
+        let mut auth = AuthData::new();
+        auth.token = tokens.access_token;
+        auth.expiration = Some(Utc::now() + chrono::Duration::seconds(tokens.expires_in));
+        auth.refresh_token = tokens.refresh_token;
+        Ok(auth)
+    }
+
+    // the client authenticates with its secret, or just names itself in the native flow
+    fn form_request(&self, url: String, pairs: &[(&str, &str)]) -> Result<Request<Full<Bytes>>, Error> {
+        let mut form = form_urlencoded::Serializer::new(String::new());
+        form.extend_pairs(pairs);
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+        if let Some(secret) = &self.client_secret {
+            let credentials = STANDARD.encode(format!("{}:{}", self.client_id, secret));
+            request = request.header(AUTHORIZATION, format!("Basic {}", credentials));
+        } else {
+            form.append_pair("client_id", &self.client_id);
+        }
+        request
+            .body(Full::new(Bytes::from(form.finish())))
+            .map_err(|t_error| Error::Network(Box::new(t_error)))
+    }
+
+    /// Checks the access token locally: signature against the SSO keys, issuer,
+    /// audience and expiration, and when `character_id` is given, that the token
+    /// belongs to that character.
+    ///
+    /// The keys are only downloaded the first time or when the SSO rotates them.
+    pub async fn validate_token(
+        &self,
+        token: &str,
+        character_id: Option<i32>,
+    ) -> Result<TokenClaims, Error> {
+        let header = jsonwebtoken::decode_header(token).map_err(invalid_token)?;
+        let key = match self.decoding_key(header.kid.as_deref()) {
+            Some(key) => key,
+            None => {
+                self.update_jwks().await?;
+                self.decoding_key(header.kid.as_deref())
+                    .ok_or_else(|| Error::InvalidToken(String::from("unknown signing key")))?
+            }
+        };
+        self.decode_claims(token, &key, character_id)
+    }
+
+    // kept apart from the key download, a profile scope can't be held across an await
+    // in the spawned refresher
+    fn decode_claims(
+        &self,
+        token: &str,
+        key: &DecodingKey,
+        character_id: Option<i32>,
+    ) -> Result<TokenClaims, Error> {
+        #[cfg(feature = "puffin")]
+        puffin::profile_scope!("sso_validate_token");
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_audience(&[self.client_id.as_str()]);
+        validation.set_issuer(&self.issuers());
+        validation.set_required_spec_claims(&["exp", "sub", "iss", "aud"]);
+        let raw = jsonwebtoken::decode::<RawClaims>(token, key, &validation)
+            .map_err(invalid_token)?
+            .claims;
+
+        let id = match raw.sub.split(':').nth(2).map(str::parse::<i32>) {
+            Some(Ok(id)) => id,
+            _ => return Err(Error::InvalidToken(format!("unexpected subject {}", raw.sub))),
+        };
+        if character_id.is_some_and(|expected| expected != id) {
+            return Err(Error::InvalidToken(format!("token belongs to character {}", id)));
+        }
+        let scopes = match raw.scp {
+            Some(Value::String(scope)) => vec![Scope::from(scope.as_str())],
+            Some(Value::Array(list)) => list
+                .iter()
+                .filter_map(|scope| scope.as_str().map(Scope::from))
+                .collect(),
+            _ => vec![],
+        };
+        Ok(TokenClaims {
+            character_id: id,
+            name: raw.name,
+            owner: raw.owner,
+            scopes,
+            expiration: DateTime::from_timestamp(raw.exp, 0).unwrap_or_default(),
+            jti: raw.jti,
+        })
+    }
+
+    // the SSO has used both forms
+    fn issuers(&self) -> Vec<String> {
+        let url = self.base_url.trim_end_matches('/');
+        let host = url.split_once("://").map(|(_, host)| host).unwrap_or(url);
+        vec![url.to_string(), host.to_string()]
+    }
+
+    fn decoding_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let jwks = self.jwks.lock().unwrap_or_else(PoisonError::into_inner);
+        let jwk = match kid {
+            Some(kid) => jwks.as_ref()?.find(kid),
+            None => jwks.as_ref()?.keys.first(),
+        }?;
+        DecodingKey::from_jwk(jwk).ok()
+    }
+
+    async fn update_jwks(&self) -> Result<(), Error> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(self.jwks_url())
+            .body(Full::new(Bytes::new()))
+            .map_err(|t_error| Error::Network(Box::new(t_error)))?;
+        let body = self.send(request).await?;
+        let jwks: JwkSet = serde_json::from_slice(&body)
+            .map_err(|t_error| Error::InvalidToken(format!("unreadable signing keys: {}", t_error)))?;
+        *self.jwks.lock().unwrap_or_else(PoisonError::into_inner) = Some(jwks);
+        Ok(())
+    }
+
+    // gives up after the timeout, the body included
+    async fn send(&self, request: Request<Full<Bytes>>) -> Result<Bytes, Error> {
+        match tokio::time::timeout(self.timeout, Sso::exchange(request)).await {
+            Ok(result) => result,
+            Err(elapsed) => Err(Error::Network(Box::new(elapsed))),
+        }
+    }
+
+    async fn exchange(request: Request<Full<Bytes>>) -> Result<Bytes, Error> {
+        let https = HttpsConnector::new();
+        let client = Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(https);
+        let res = client.request(request).await?;
+        let status = res.status();
+        let body = res.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            let status = status.as_u16();
+            return Err(match serde_json::from_slice::<OAuthError>(&body) {
+                Ok(refused) => Error::Sso {
+                    error: refused.error,
+                    description: refused.error_description,
+                },
+                Err(_) => Error::EsiStatus(status),
+            });
+        }
+        Ok(body)
+    }
+}
+
+fn invalid_token(t_error: jsonwebtoken::errors::Error) -> Error {
+    Error::InvalidToken(t_error.to_string())
+}
@@ -1,3 +1,6 @@
 pub mod auth_service;
+pub mod error;
 pub mod esi;
 pub mod objects;
+
+pub use error::Error;
@@ -1,19 +1,18 @@
+use crate::esi::Scope;
+use crate::Error;
 use chrono::prelude::*;
-use rusqlite::Error;
 
 pub trait EsiObject {
     fn retrieve() -> Result<bool, Error>;
 }
 
-pub enum TelescopeDbError {
-    NoConnection,
-}
-
 #[derive(Clone, PartialEq)]
 pub struct AuthData {
     pub token: String,
     pub expiration: Option<DateTime<Utc>>,
     pub refresh_token:String,
+    /// Scopes the character granted with this token.
+    pub scopes: Vec<Scope>,
 }
 
 impl AuthData{
@@ -21,7 +20,55 @@ impl AuthData{
         AuthData {
             token: String::new(),
             expiration: None,
-            refresh_token: String::new()
+            refresh_token: String::new(),
+            scopes: Vec::new(),
+        }
+    }
+}
+
+impl Default for AuthData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Login started at the SSO and not completed yet.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LoginSession {
+    pub state: String,
+    /// PKCE verifier of the native flow.
+    pub verifier: Option<String>,
+    /// Set when the login upgrades the scopes of a known character.
+    pub character_id: Option<i32>,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// What a completed login found out about the character.
+#[derive(Clone, PartialEq)]
+pub enum LoginOutcome {
+    /// The character logged in for the first time or from the account already known.
+    LoggedIn(Character),
+    /// The character logged in from another account than the stored one, it is
+    /// flagged as transferred until the application acknowledges it.
+    Transferred {
+        character: Character,
+        previous_owner: String,
+    },
+}
+
+impl LoginOutcome {
+    pub fn character(&self) -> &Character {
+        match self {
+            LoginOutcome::LoggedIn(character) => character,
+            LoginOutcome::Transferred { character, .. } => character,
+        }
+    }
+
+    pub fn into_character(self) -> Character {
+        match self {
+            LoginOutcome::LoggedIn(character) => character,
+            LoginOutcome::Transferred { character, .. } => character,
         }
     }
 }
@@ -35,6 +82,13 @@ pub struct Character {
     pub alliance: Option<Alliance>,
     pub photo: Option<String>,
     pub location: i32,
+    pub auth: Option<AuthData>,
+    /// Hash of the account owning the character, as the SSO last reported it.
+    pub owner: Option<String>,
+    /// Set when the character changed account since it was first seen.
+    pub transferred: bool,
+    /// Set when the SSO refused the tokens for good, cleared by the next login.
+    pub needs_reauth: bool,
 }
 
 impl Character {
@@ -47,6 +101,10 @@ impl Character {
             alliance: None,
             photo: None,
             location: 0,
+            auth: None,
+            owner: None,
+            transferred: false,
+            needs_reauth: false,
         }
     }
 }
@@ -0,0 +1,344 @@
+// Stand-in for the EVE SSO and ESI so the authenticated paths run offline.
+#![allow(dead_code)]
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response};
+use hyper_util::rt::TokioIo;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rfesi::prelude::{Esi, EsiBuilder};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use webb::esi::{EsiManager, EsiManagerBuilder};
+
+#[cfg(feature = "crypted-db")]
+use webb::esi::DatabaseKey;
+
+pub const USER_AGENT: &str = "telescope/v0";
+pub const CLIENT_ID: &str = "a4b4a52e65fe4dce95eec1fab224407c";
+pub const SECRET_KEY: &str = "AFgvjrXi8rRpYbhsYe5hQFpPk266jyU40QlPYIam";
+pub const CALLBACK: &str = "http://localhost:4500/login";
+
+pub const SOLAR_SYSTEM: i32 = 30000142;
+pub const CHARACTER_ID: i32 = 95103254;
+pub const CORPORATION_ID: i32 = 98660791;
+pub const ALLIANCE_ID: i32 = 99003581;
+pub const OWNER: &str = "8PmzCeTKb4VFUDrHLc/AeZXDSWM=";
+
+// removes the database along with the WAL files left by a failed run
+pub fn remove_database(path_str: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let file = format!("{}{}", path_str, suffix);
+        let path = Path::new(&file);
+        if path.exists() && path.is_file() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+pub fn open_manager(path_str: &str) -> Result<EsiManager, webb::Error> {
+    EsiManager::new(
+        USER_AGENT,
+        CLIENT_ID,
+        SECRET_KEY,
+        CALLBACK,
+        vec![],
+        path_str.to_string(),
+    )
+}
+
+#[cfg(feature = "crypted-db")]
+pub fn open_manager_with_key(path_str: &str, key: DatabaseKey) -> Result<EsiManager, webb::Error> {
+    EsiManager::new_with_key(
+        USER_AGENT,
+        CLIENT_ID,
+        SECRET_KEY,
+        CALLBACK,
+        vec![],
+        path_str.to_string(),
+        key,
+    )
+}
+
+// creates a manager over a fresh database file
+pub fn new_manager(path_str: &str) -> EsiManager {
+    remove_database(path_str);
+    open_manager(path_str).unwrap()
+}
+
+const SIGNING_KEY: &[u8] = include_bytes!("../keys/jwtRS256.key");
+const JWKS: &str = include_str!("../keys/jwks.json");
+
+// just the operations the library looks up
+const SPEC: &str = r#"{"paths":{
+    "/v1/characters/{character_id}/location/":{"get":{"operationId":"get_characters_character_id_location"}},
+    "/v5/characters/{character_id}/":{"get":{"operationId":"get_characters_character_id"}},
+    "/v3/characters/{character_id}/portrait/":{"get":{"operationId":"get_characters_character_id_portrait"}},
+    "/v5/corporations/{corporation_id}/":{"get":{"operationId":"get_corporations_corporation_id"}},
+    "/v4/alliances/{alliance_id}/":{"get":{"operationId":"get_alliances_alliance_id"}}
+}}"#;
+
+#[derive(Default)]
+struct MockState {
+    token_requests: usize,
+    token_delay: Duration,
+    token_error: Option<String>,
+    owner: Option<String>,
+    refresh_tokens: Vec<String>,
+    revoked: Vec<String>,
+    jwks_requests: usize,
+    jwks_down: bool,
+    datasources: Vec<String>,
+}
+
+pub struct MockEve {
+    pub url: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockEve {
+    /// Serves on a free local port until the runtime shuts down.
+    pub async fn start() -> MockEve {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let shared = Arc::clone(&state);
+        let base = url.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = Arc::clone(&shared);
+                let base = base.clone();
+                tokio::spawn(async move {
+                    let service =
+                        service_fn(move |req| handle(base.clone(), Arc::clone(&state), req));
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        MockEve { url, state }
+    }
+
+    /// Points the manager to the mock.
+    pub fn attach(&self, mon: &mut EsiManager) {
+        mon.esi = self.esi();
+        mon.sso.base_url = self.url.clone();
+    }
+
+    /// Builder of a manager using the mock as ESI and SSO, the way an application
+    /// would configure a local stand-in.
+    pub fn builder(&self, database_path: &str) -> EsiManagerBuilder {
+        EsiManager::builder()
+            .user_agent(USER_AGENT)
+            .client_id(CLIENT_ID)
+            .client_secret(SECRET_KEY)
+            .callback_url(CALLBACK)
+            .database_path(database_path)
+            .esi_url(&self.url)
+            .sso_url(&self.url)
+    }
+
+    /// Access token signed like the SSO does, valid for `minutes`.
+    pub fn token(&self, character_id: i32, owner: &str, jti: &str, minutes: i64) -> String {
+        sign_token(&self.url, character_id, owner, jti, minutes)
+    }
+
+    /// ESI client talking to the mock instead of the real services.
+    pub fn esi(&self) -> Esi {
+        EsiBuilder::new()
+            .user_agent(USER_AGENT)
+            .client_id(CLIENT_ID)
+            .client_secret(SECRET_KEY)
+            .callback_url(CALLBACK)
+            .base_api_url(&self.url)
+            .token_url(&format!("{}v2/oauth/token", self.url))
+            .spec_url(&format!("{}spec", self.url))
+            .build()
+            .unwrap()
+    }
+
+    pub fn token_requests(&self) -> usize {
+        self.state.lock().unwrap().token_requests
+    }
+
+    /// Refresh tokens received by the token endpoint, in order.
+    pub fn refresh_tokens(&self) -> Vec<String> {
+        self.state.lock().unwrap().refresh_tokens.clone()
+    }
+
+    /// Tokens received by the revoke endpoint, in order.
+    pub fn revoked(&self) -> Vec<String> {
+        self.state.lock().unwrap().revoked.clone()
+    }
+
+    /// `datasource` asked for by every ESI call, in order.
+    pub fn datasources(&self) -> Vec<String> {
+        self.state.lock().unwrap().datasources.clone()
+    }
+
+    pub fn jwks_requests(&self) -> usize {
+        self.state.lock().unwrap().jwks_requests
+    }
+
+    /// Slows the token endpoint down so concurrent refreshes overlap.
+    pub fn set_token_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().token_delay = delay;
+    }
+
+    /// Account hash put in the tokens from now on, [`OWNER`] until changed.
+    pub fn set_owner(&self, owner: &str) {
+        self.state.lock().unwrap().owner = Some(owner.to_string());
+    }
+
+    /// Makes the signing keys unavailable, or available again.
+    pub fn set_jwks_down(&self, down: bool) {
+        self.state.lock().unwrap().jwks_down = down;
+    }
+
+    /// Makes the token endpoint answer with the OAuth `error`, or work again with `None`.
+    pub fn set_token_error(&self, error: Option<&str>) {
+        self.state.lock().unwrap().token_error = error.map(String::from);
+    }
+}
+
+fn sign_token(url: &str, character_id: i32, owner: &str, jti: &str, minutes: i64) -> String {
+    let now = chrono::Utc::now();
+    let claims = json!({
+        "aud": [CLIENT_ID, "EVE Online"],
+        "azp": CLIENT_ID,
+        "exp": (now + chrono::Duration::minutes(minutes)).timestamp(),
+        "iat": now.timestamp(),
+        "iss": url.trim_end_matches('/'),
+        "jti": jti,
+        "kid": "JWT-Signature-Key",
+        "name": "Rain Agnon",
+        "owner": owner,
+        "region": "world",
+        "scp": ["esi-location.read_location.v1"],
+        "sub": format!("CHARACTER:EVE:{}", character_id),
+        "tenant": "tranquility",
+        "tier": "live",
+    });
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some(String::from("JWT-Signature-Key"));
+    let key = EncodingKey::from_rsa_pem(SIGNING_KEY).unwrap();
+    jsonwebtoken::encode(&header, &claims, &key).unwrap()
+}
+
+async fn handle(
+    url: String,
+    state: Arc<Mutex<MockState>>,
+    req: Request<hyper::body::Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let path = req.uri().path().to_string();
+    let query = req.uri().query().unwrap_or_default().to_string();
+    let body = req.into_body().collect().await?.to_bytes();
+    let body = String::from_utf8_lossy(&body).to_string();
+
+    if !path.contains("oauth") {
+        if let Some(datasource) = form_value(&query, "datasource") {
+            state.lock().unwrap().datasources.push(datasource);
+        }
+    }
+
+    let (status, answer) = if path == "/spec" || path == "/_latest/swagger.json" {
+        (200, SPEC.to_string())
+    } else if path == "/oauth/jwks" {
+        let mut state = state.lock().unwrap();
+        state.jwks_requests += 1;
+        if state.jwks_down {
+            (503, String::from("{}"))
+        } else {
+            (200, JWKS.to_string())
+        }
+    } else if path == "/v2/oauth/revoke" {
+        let mut state = state.lock().unwrap();
+        if let Some(token) = form_value(&body, "token") {
+            state.revoked.push(token);
+        }
+        (200, String::new())
+    } else if path == "/v2/oauth/token" {
+        let (count, delay, error, owner) = {
+            let mut state = state.lock().unwrap();
+            state.token_requests += 1;
+            if let Some(token) = form_value(&body, "refresh_token") {
+                state.refresh_tokens.push(token);
+            }
+            let owner = state.owner.clone().unwrap_or_else(|| OWNER.to_string());
+            (state.token_requests, state.token_delay, state.token_error.clone(), owner)
+        };
+        tokio::time::sleep(delay).await;
+        if let Some(error) = error {
+            let answer = json!({ "error": error, "error_description": "refused by the mock" });
+            return Ok(json_response(400, answer.to_string()));
+        }
+        let token = sign_token(&url, CHARACTER_ID, &owner, &format!("access-{}", count), 20);
+        let answer = json!({
+            "access_token": token,
+            "expires_in": 1199,
+            "token_type": "Bearer",
+            "refresh_token": format!("refresh-{}", count),
+        });
+        (200, answer.to_string())
+    } else if path.ends_with("/location/") {
+        (200, format!(r#"{{"solar_system_id":{}}}"#, SOLAR_SYSTEM))
+    } else if path.ends_with("/portrait/") {
+        let portrait = format!("{}portrait.png", url);
+        (200, json!({ "px128x128": portrait }).to_string())
+    } else if path.starts_with("/v5/characters/") {
+        let answer = json!({
+            "alliance_id": ALLIANCE_ID,
+            "birthday": "2015-03-24T11:37:00Z",
+            "bloodline_id": 3,
+            "corporation_id": CORPORATION_ID,
+            "gender": "male",
+            "name": "Rain Agnon",
+            "race_id": 2,
+        });
+        (200, answer.to_string())
+    } else if path.starts_with("/v5/corporations/") {
+        let answer = json!({
+            "alliance_id": ALLIANCE_ID,
+            "ceo_id": CHARACTER_ID,
+            "creator_id": CHARACTER_ID,
+            "member_count": 1,
+            "name": "Alfa Corp",
+            "tax_rate": 0.1,
+        });
+        (200, answer.to_string())
+    } else if path.starts_with("/v4/alliances/") {
+        let answer = json!({
+            "creator_corporation_id": CORPORATION_ID,
+            "creator_id": CHARACTER_ID,
+            "date_founded": "2016-06-26T21:00:00Z",
+            "name": "Beta alliance",
+            "ticker": "BETA",
+        });
+        (200, answer.to_string())
+    } else {
+        (404, String::from("{}"))
+    };
+
+    Ok(json_response(status, answer))
+}
+
+fn json_response(status: u16, answer: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Full::new(Bytes::from(answer)))
+        .unwrap()
+}
+
+fn form_value(body: &str, name: &str) -> Option<String> {
+    form_urlencoded::parse(body.as_bytes())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+}
@@ -0,0 +1,135 @@
+mod common;
+
+#[cfg(test)]
+mod esi_config {
+    use crate::common::{self, remove_database};
+    use std::fs;
+    use std::path::Path;
+    use std::time::Duration;
+    use webb::esi::{Data, Datasource, EsiManager, EsiManagerBuilder, Scope};
+
+    const CONFIG: &str = r#"
+        user_agent = "telescope/v0"
+        client_id = "a4b4a52e65fe4dce95eec1fab224407c"
+        secret_key = "AFgvjrXi8rRpYbhsYe5hQFpPk266jyU40QlPYIam"
+        callback_url = "http://localhost:4500/login"
+        scopes = ["publicData", "esi-location.read_location.v1"]
+        database_path = "tests/databases/test_config.db"
+        esi_url = "http://127.0.0.1:8080"
+        datasource = "singularity"
+        http_timeout = 30
+    "#;
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn data_from_toml() {
+        let data = Data::from_toml(CONFIG).unwrap();
+        assert_eq!(data.client_id, common::CLIENT_ID);
+        assert_eq!(data.scopes, vec!["publicData", "esi-location.read_location.v1"]);
+        assert_eq!(data.esi_url.as_deref(), Some("http://127.0.0.1:8080"));
+        assert_eq!(data.sso_url, None);
+        assert_eq!(data.datasource, Datasource::Singularity);
+        assert_eq!(data.http_timeout, Some(30));
+        assert_eq!(data.database_key, None);
+        let printed = format!("{:?}", data);
+        assert!(printed.contains(common::CLIENT_ID));
+        assert!(!printed.contains(common::SECRET_KEY));
+
+        // a typo must not go unnoticed
+        let result = Data::from_toml("client_idd = \"abc\"");
+        assert!(matches!(result, Err(webb::Error::Config(_))));
+        let result = Data::from_toml("http_timeout = \"soon\"");
+        assert!(matches!(result, Err(webb::Error::Config(_))));
+
+        let file = std::env::temp_dir().join(format!("webb_config_{}.toml", std::process::id()));
+        fs::write(&file, CONFIG).unwrap();
+        assert_eq!(Data::from_toml_file(&file).unwrap(), data);
+        let _ = fs::remove_file(&file);
+        assert!(matches!(Data::from_toml_file(&file), Err(webb::Error::Io(_))));
+    }
+
+    #[test]
+    fn data_from_environment() {
+        let data = Data::from_vars(vars(&[
+            ("WEBB_USER_AGENT", common::USER_AGENT),
+            ("WEBB_CLIENT_ID", common::CLIENT_ID),
+            ("WEBB_SCOPES", "publicData  esi-location.read_location.v1"),
+            ("WEBB_DATABASE_KEY", "correct horse"),
+            ("WEBB_SSO_URL", "http://127.0.0.1:8081/"),
+            ("WEBB_DATASOURCE", "Singularity"),
+            ("WEBB_HTTP_TIMEOUT", "15"),
+            ("HOME", "/root"),
+        ]))
+        .unwrap();
+        assert_eq!(data.user_agent, common::USER_AGENT);
+        assert_eq!(data.scopes, vec!["publicData", "esi-location.read_location.v1"]);
+        assert_eq!(data.database_key.as_deref(), Some("correct horse"));
+        assert!(!format!("{:?}", data).contains("correct horse"));
+        assert_eq!(data.http_timeout, Some(15));
+        assert_eq!(data.sso_url.as_deref(), Some("http://127.0.0.1:8081/"));
+        assert_eq!(data.datasource, Datasource::Singularity);
+        assert_eq!(data.callback_url, "");
+        assert_eq!(Data::from_vars(vars(&[])).unwrap().datasource, Datasource::Tranquility);
+
+        let result = Data::from_vars(vars(&[("WEBB_HTTP_TIMEOUT", "15s")]));
+        assert!(matches!(result, Err(webb::Error::Config(_))));
+        let result = Data::from_vars(vars(&[("WEBB_DATASOURCE", "serenity")]));
+        assert!(matches!(result, Err(webb::Error::Config(_))));
+    }
+
+    #[test]
+    fn builder_reports_invalid_settings() {
+        let path_str = "tests/databases/test_config_invalid.db";
+        remove_database(path_str);
+        let builder = EsiManager::builder()
+            .user_agent(common::USER_AGENT)
+            .client_id(common::CLIENT_ID)
+            .client_secret(common::SECRET_KEY)
+            .database_path(path_str);
+
+        let result = builder.clone().build();
+        assert!(matches!(result, Err(webb::Error::Config(ref reason)) if reason.contains("callback_url")));
+        let result = builder
+            .clone()
+            .callback_url(common::CALLBACK)
+            .esi_url("esi.evetech.net")
+            .build();
+        assert!(matches!(result, Err(webb::Error::Config(ref reason)) if reason.contains("esi_url")));
+        let result = builder.clone().callback_url(common::CALLBACK).sso_url("").build();
+        assert!(matches!(result, Err(webb::Error::Config(ref reason)) if reason.contains("sso_url")));
+        // nothing is written before the settings are known to be good
+        assert!(!Path::new(path_str).exists());
+
+        let data = Data::from_toml("client_id = \"a4b4a52e65fe4dce95eec1fab224407c\"").unwrap();
+        let result = EsiManagerBuilder::from_data(&data).unwrap().build();
+        assert!(matches!(result, Err(webb::Error::Config(_))));
+    }
+
+    #[test]
+    fn builder_from_data() {
+        let data = Data::from_toml(CONFIG).unwrap();
+        remove_database(&data.database_path);
+        let mon = EsiManagerBuilder::from_data(&data)
+            .unwrap()
+            .http_timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        assert!(Path::new(&data.database_path).exists());
+        assert_eq!(mon.callback_url, common::CALLBACK);
+        assert_eq!(mon.datasource, Datasource::Singularity);
+        assert!(mon.characters.is_empty());
+
+        let auth_info = mon.get_authorize_url().unwrap();
+        let scopes = Scope::join(&[Scope::PublicData, Scope::ReadLocation]).replace(' ', "%20");
+        assert!(auth_info.authorization_url.contains(&format!("scope={}&", scopes)));
+        // the test server logs in on its own SSO
+        assert!(auth_info
+            .authorization_url
+            .starts_with("https://sisilogin.testeveonline.com/v2/oauth/authorize?"));
+    }
+}
@@ -1,5 +1,27 @@
+mod common;
+
 #[cfg(test)]
 mod esi_manager {
+    use crate::common::{self, MockEve};
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty};
+    use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use webb::auth_service::{AuthService2, CallbackPages, CallbackParams, PendingStates};
+    use webb::esi::{Datasource, RefreshEvent, RefreshOptions, Scope};
+    use webb::objects::{Alliance, AuthData, Character, Corporation, LoginOutcome};
+    use rfesi::prelude::AuthenticationInformation;
+
+    fn auth_data(token: &str, minutes: i64) -> AuthData {
+        let mut auth = AuthData::new();
+        auth.token = token.to_string();
+        auth.refresh_token = format!("{}-refresh", token);
+        auth.expiration = Some(chrono::Utc::now() + chrono::Duration::minutes(minutes));
+        auth
+    }
+
     /*use std::fs;
     use std::path::Path;
     use webb::objects::{Alliance, Character, Corporation};
@@ -281,12 +303,12 @@ mod esi_manager {
             scope,
             Some(path_c),
         );
-        let auth_info = esimon.esi.get_authorize_url().unwrap();
+        let auth_info = esimon.get_authorize_url().unwrap();
 
         match open::that(&auth_info.authorization_url) {
             Ok(()) => {
                 let mut vec = vec![];
-                let res = match webb::esi::EsiManager::priv_launch_auth_server(4500).await {
+                let res = match webb::esi::EsiManager::launch_auth_server(4500).await {
                     Ok(a) => a,
                     Err(t_error) => panic!("An error occurred: {}", t_error),
                 };
@@ -342,4 +364,1003 @@ mod esi_manager {
             assert_eq!(chars.len(), 1);
         }
     }*/
+
+    // hits the callback server until it is bound and returns the response status
+    async fn send_callback(url: &str) -> u16 {
+        fetch_callback(url).await.0
+    }
+
+    // same as send_callback, also returning the page
+    async fn fetch_callback(url: &str) -> (u16, String) {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        for _ in 0..50 {
+            if let Ok(res) = client.get(url.parse().unwrap()).await {
+                let status = res.status().as_u16();
+                let body = res.into_body().collect().await.unwrap().to_bytes();
+                return (status, String::from_utf8_lossy(&body).to_string());
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("callback server never came up");
+    }
+
+    #[tokio::test]
+    async fn auth_server_returns_callback() {
+        let server = tokio::spawn(webb::esi::EsiManager::launch_auth_server(4510));
+        let status = send_callback("http://127.0.0.1:4510/login?code=abc&state=xyz").await;
+        assert_eq!(status, 200);
+        match server.await.unwrap() {
+            Ok((code, state)) => {
+                assert_eq!(code, "abc");
+                assert_eq!(state, "xyz");
+            }
+            Err(t_error) => panic!("Error: {}", t_error),
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_server_times_out() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4511));
+        let result =
+            webb::esi::EsiManager::launch_auth_server_on(addr, Duration::from_millis(100), None)
+                .await;
+        assert!(result.is_err());
+        // the port must be released once the server gives up
+        assert!(std::net::TcpListener::bind(addr).is_ok());
+    }
+
+    #[tokio::test]
+    async fn db_auth_per_character() {
+        let path_str = "tests/databases/test_auth.db";
+        let mut mon = common::new_manager(path_str);
+
+        let mut main = Character::new();
+        main.id = 95103254;
+        main.name = "Rain Agnon".to_string();
+        main.auth = Some(auth_data("main", 20));
+        let mut alt = Character::new();
+        alt.id = 2132411;
+        alt.name = "Alt Agnon".to_string();
+        alt.auth = Some(auth_data("alt", -5));
+        mon.write_character(&main).unwrap();
+        mon.write_character(&alt).unwrap();
+
+        let mut mon = webb::esi::EsiManager::new(
+            common::USER_AGENT,
+            common::CLIENT_ID,
+            common::SECRET_KEY,
+            common::CALLBACK,
+            vec![],
+            path_str.to_string(),
+        )
+        .unwrap();
+        assert_eq!(mon.characters.len(), 2);
+        let chars = mon.read_characters(Some(vec![95103254])).unwrap();
+        assert_eq!(chars[0].auth.as_ref().unwrap().token, "main");
+        let chars = mon.read_characters(Some(vec![2132411])).unwrap();
+        assert_eq!(chars[0].auth.as_ref().unwrap().token, "alt");
+        assert!(mon.valid_token(95103254).await);
+        assert!(!mon.valid_token(2132411).await);
+
+        mon.remove_characters(Some(vec![2132411])).unwrap();
+        assert!(!mon.valid_token(2132411).await);
+        let chars = mon.read_characters(None).unwrap();
+        assert_eq!(chars.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn auth_server_rejects_unknown_state() {
+        let states = PendingStates::new();
+        states.insert("xyz");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4512));
+        let server = tokio::spawn(webb::esi::EsiManager::launch_auth_server_on(
+            addr,
+            Duration::from_secs(5),
+            Some(states.clone()),
+        ));
+        let status = send_callback("http://127.0.0.1:4512/login?code=abc&state=forged").await;
+        assert_eq!(status, 400);
+        let status = send_callback("http://127.0.0.1:4512/login?code=abc&state=xyz").await;
+        assert_eq!(status, 200);
+        let (code, state) = server.await.unwrap().unwrap();
+        assert_eq!((code.as_str(), state.as_str()), ("abc", "xyz"));
+    }
+
+    #[tokio::test]
+    async fn auth_user_rejects_invalid_state() {
+        let mut mon = common::new_manager("tests/databases/test_state.db");
+        let auth_info = AuthenticationInformation {
+            authorization_url: String::new(),
+            state: String::from("issued"),
+            pkce_verifier: None,
+        };
+        let result = mon
+            .auth_user(auth_info, (String::from("code"), String::from("forged")))
+            .await;
+        assert!(matches!(result, Err(webb::Error::AuthStateMismatch)));
+
+        // a state that matches but isn't pending was already consumed
+        let auth_info = AuthenticationInformation {
+            authorization_url: String::new(),
+            state: String::from("issued"),
+            pkce_verifier: None,
+        };
+        let result = mon
+            .auth_user(auth_info, (String::from("code"), String::from("issued")))
+            .await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+    }
+
+    #[test]
+    fn authorize_url_registers_pending_state() {
+        let mon = common::new_manager("tests/databases/test_pending.db");
+        let first = mon.get_authorize_url().unwrap();
+        let second = mon.get_authorize_url().unwrap();
+        assert_eq!(mon.pending_logins.len(), 2);
+        assert!(mon.pending_logins.contains(&first.state));
+        assert!(mon.pending_logins.contains(&second.state));
+    }
+
+    #[test]
+    fn db_character_without_corp_or_alliance() {
+        let mut mon = common::new_manager("tests/databases/test_noally.db");
+        let mut zchar = Character::new();
+        zchar.id = 2132411;
+        zchar.name = "Rain Agnon".to_string();
+        mon.write_character(&zchar).unwrap();
+
+        // the second write goes through the update path
+        zchar.name = "Rain Agnon II".to_string();
+        zchar.location = 30000142;
+        assert_eq!(mon.write_character(&zchar).unwrap(), 1);
+
+        let chars = mon.read_characters(Some(vec![2132411])).unwrap();
+        assert_eq!(chars[0].name, "Rain Agnon II");
+        assert_eq!(chars[0].corp, None);
+        assert_eq!(chars[0].alliance, None);
+    }
+
+    #[test]
+    fn db_unreachable_path_is_an_error() {
+        let result = webb::esi::EsiManager::new(
+            common::USER_AGENT,
+            common::CLIENT_ID,
+            common::SECRET_KEY,
+            common::CALLBACK,
+            vec![],
+            String::from("tests/databases/missing/test.db"),
+        );
+        assert!(matches!(result, Err(webb::Error::Database(_))));
+
+        let mut mon = common::new_manager("tests/databases/test_unreachable.db");
+        mon.path = String::from("tests/databases/missing/test.db");
+        assert!(mon.write_corporation(&Corporation::new()).is_err());
+        assert!(mon.read_characters(None).is_err());
+        assert!(mon.remove_alliance(None).is_err());
+    }
+
+    #[test]
+    fn invalid_esi_configuration_is_an_error() {
+        let result = webb::esi::EsiManager::new(
+            "telescope\nv0",
+            common::CLIENT_ID,
+            common::SECRET_KEY,
+            common::CALLBACK,
+            vec![],
+            String::from("tests/databases/test_badagent.db"),
+        );
+        assert!(matches!(result, Err(webb::Error::Esi(_))));
+    }
+
+    #[test]
+    fn db_write_characters_batch() {
+        let mut mon = common::new_manager("tests/databases/test_batch.db");
+        let corp = Corporation {
+            id: 98660791,
+            name: "Alfa Corp".to_string(),
+        };
+        let alliance = Alliance {
+            id: 99003581,
+            name: "Beta alliance".to_string(),
+        };
+        let roster: Vec<Character> = (1..=50)
+            .map(|id| {
+                let mut zchar = Character::new();
+                zchar.id = id;
+                zchar.name = format!("pilot {}", id);
+                zchar.corp = Some(corp.clone());
+                zchar.alliance = Some(alliance.clone());
+                zchar.photo = Some(format!("https://images.evetech.net/characters/{}/portrait", id));
+                zchar
+            })
+            .collect();
+        assert_eq!(mon.write_characters(&roster).unwrap(), 50);
+        assert_eq!(mon.read_characters(None).unwrap().len(), 50);
+        assert_eq!(mon.read_corporation(None).unwrap().len(), 1);
+        assert_eq!(mon.read_alliance(None).unwrap().len(), 1);
+
+        // writing again updates in place, keeping the portrait when none is given
+        let mut zchar = roster[0].clone();
+        zchar.photo = None;
+        zchar.alliance = None;
+        zchar.corp = Some(Corporation {
+            id: 98660791,
+            name: "Alfa Corp renamed".to_string(),
+        });
+        assert_eq!(mon.write_character(&zchar).unwrap(), 1);
+        let chars = mon.read_characters(Some(vec![1])).unwrap();
+        assert_eq!(chars[0].photo, roster[0].photo);
+        assert_eq!(chars[0].alliance, None);
+        assert_eq!(chars[0].corp.as_ref().unwrap().name, "Alfa Corp renamed");
+        assert_eq!(mon.read_characters(None).unwrap().len(), 50);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn db_async_roster() {
+        let mon = common::new_manager("tests/databases/test_async.db");
+        let roster: Vec<Character> = (1..=10)
+            .map(|id| {
+                let mut zchar = Character::new();
+                zchar.id = id;
+                zchar.name = format!("pilot {}", id);
+                zchar.corp = Some(Corporation {
+                    id: 1000 + id,
+                    name: format!("corporation {}", id),
+                });
+                zchar
+            })
+            .collect();
+        assert_eq!(mon.write_characters_async(&roster).await.unwrap(), 10);
+
+        // reads only need a shared reference so they can run side by side
+        let (chars, corps) = tokio::join!(
+            mon.read_characters_async(None),
+            mon.read_corporation_async(Some(vec![1001, 1002]))
+        );
+        assert_eq!(chars.unwrap().len(), 10);
+        assert_eq!(corps.unwrap().len(), 2);
+
+        let reader = mon.clone();
+        let task = tokio::spawn(async move { reader.read_characters_async(Some(vec![3])).await });
+        assert_eq!(task.await.unwrap().unwrap()[0].name, "pilot 3");
+
+        assert_eq!(mon.remove_characters_async(Some(vec![1, 2])).await.unwrap(), 2);
+        assert_eq!(mon.read_characters(None).unwrap().len(), 8);
+    }
+
+    // stores a character whose token expired, talking to the mock
+    fn expired_character(mon: &mut webb::esi::EsiManager, mock: &MockEve) -> i32 {
+        mock.attach(mon);
+        let mut zchar = Character::new();
+        zchar.id = 95103254;
+        zchar.name = "Rain Agnon".to_string();
+        zchar.auth = Some(auth_data("stale", -5));
+        mon.write_character(&zchar).unwrap();
+        mon.characters.insert(zchar);
+        95103254
+    }
+
+    #[tokio::test]
+    async fn rotated_token_is_kept_when_validation_fails() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_refresh_unvalidated.db");
+        let id = expired_character(&mut mon, &mock);
+
+        // the keys can't be downloaded, the new tokens must be saved anyway
+        mock.set_jwks_down(true);
+        assert!(matches!(mon.refresh_token(id).await, Err(webb::Error::EsiStatus(503))));
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert_eq!(chars[0].auth.as_ref().unwrap().refresh_token, "refresh-1");
+        assert!(!chars[0].needs_reauth);
+
+        mock.set_jwks_down(false);
+        mon.refresh_token(id).await.unwrap();
+        assert_eq!(mock.refresh_tokens(), vec!["stale-refresh", "refresh-1"]);
+        assert!(mon.characters_needing_reauth().is_empty());
+
+        // a token issued to another character is not kept
+        let mut other = Character::new();
+        other.id = 2132411;
+        other.name = "Pleb Agnon".to_string();
+        other.auth = Some(auth_data("other", -5));
+        mon.write_character(&other).unwrap();
+        mon.characters.insert(other);
+        let result = mon.refresh_token(2132411).await;
+        assert!(matches!(result, Err(webb::Error::InvalidToken(_))));
+        assert!(mon.characters.auth(2132411).is_none());
+        let chars = mon.read_characters(Some(vec![2132411])).unwrap();
+        assert!(chars[0].auth.is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_token_without_refresh_token_is_reported() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_no_refresh_token.db");
+        mock.attach(&mut mon);
+        let mut zchar = Character::new();
+        zchar.id = 95103254;
+        zchar.name = "Rain Agnon".to_string();
+        let mut auth = auth_data("stale", -5);
+        auth.refresh_token = String::new();
+        zchar.auth = Some(auth);
+        mon.write_character(&zchar).unwrap();
+        mon.characters.insert(zchar);
+        let id = 95103254;
+
+        let result = mon.get_location(id).await;
+        assert!(matches!(result, Err(webb::Error::TokenExpired(found)) if found == id));
+        assert_eq!(mock.token_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn stuck_sso_request_times_out() {
+        let mock = MockEve::start().await;
+        let path_str = "tests/databases/test_sso_timeout.db";
+        common::remove_database(path_str);
+        let mut mon = mock
+            .builder(path_str)
+            .http_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap();
+        let id = expired_character(&mut mon, &mock);
+
+        mock.set_token_delay(Duration::from_secs(5));
+        let started = std::time::Instant::now();
+        let result = mon.refresh_token(id).await;
+        assert!(matches!(result, Err(webb::Error::Network(_))));
+        assert!(started.elapsed() < Duration::from_secs(2));
+        // the lock of the character was let go
+        mock.set_token_delay(Duration::ZERO);
+        mon.refresh_token(id).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn expired_token_is_refreshed_before_the_call() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_refresh.db");
+        let id = expired_character(&mut mon, &mock);
+
+        assert_eq!(mon.get_location(id).await.unwrap(), common::SOLAR_SYSTEM);
+        assert_eq!(mock.refresh_tokens(), vec!["stale-refresh"]);
+        assert!(mon.valid_token(id).await);
+        // the rotated token is stored before the call goes on
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        let auth = chars[0].auth.as_ref().unwrap();
+        assert_eq!(auth.refresh_token, "refresh-1");
+        assert_eq!(mon.token_claims(id).await.unwrap().jti, "access-1");
+
+        // a valid token is used as is
+        mon.get_location(id).await.unwrap();
+        assert_eq!(mock.token_requests(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_calls_refresh_once() {
+        let mock = MockEve::start().await;
+        mock.set_token_delay(Duration::from_millis(200));
+        let mut mon = common::new_manager("tests/databases/test_single_flight.db");
+        let id = expired_character(&mut mon, &mock);
+
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let mut clone = mon.clone();
+                tokio::spawn(async move { clone.get_location(id).await })
+            })
+            .collect();
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), common::SOLAR_SYSTEM);
+        }
+        assert_eq!(mock.token_requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_not_authenticated() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_no_token.db");
+        mock.attach(&mut mon);
+        let mut zchar = Character::new();
+        zchar.id = 2132411;
+        zchar.name = "Alt Agnon".to_string();
+        mon.write_character(&zchar).unwrap();
+        mon.characters.insert(zchar);
+
+        let result = mon.get_location(2132411).await;
+        assert!(matches!(result, Err(webb::Error::NotAuthenticated(2132411))));
+        assert_eq!(mock.token_requests(), 0);
+    }
+
+    #[tokio::test]
+    async fn clones_share_the_tokens() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_shared_roster.db");
+        let id = expired_character(&mut mon, &mock);
+        let mut worker = mon.clone();
+
+        worker.refresh_token(id).await.unwrap();
+        assert_eq!(mon.characters.auth(id).unwrap().refresh_token, "refresh-1");
+        // the original spends the refresh token the worker got back
+        mon.refresh_token(id).await.unwrap();
+        assert_eq!(mock.refresh_tokens(), vec!["stale-refresh", "refresh-1"]);
+        assert_eq!(worker.characters.auth(id).unwrap().refresh_token, "refresh-2");
+
+        worker.remove_characters(Some(vec![id])).unwrap();
+        assert!(mon.characters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn logout_revokes_and_forgets_the_token() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_logout.db");
+        let id = expired_character(&mut mon, &mock);
+        let mut alt = Character::new();
+        alt.id = 2132411;
+        alt.name = "Alt Agnon".to_string();
+        alt.auth = Some(auth_data("alt", 20));
+        mon.write_character(&alt).unwrap();
+        mon.characters.insert(alt);
+        mon.active_character = Some(id);
+
+        mon.logout(id, false).await.unwrap();
+        assert_eq!(mock.revoked(), vec!["stale-refresh"]);
+        assert!(mon.characters.auth(id).is_none());
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert!(chars[0].auth.is_none());
+        assert_eq!(mon.active_character, Some(2132411));
+
+        // without a token there is nothing left to revoke
+        mon.logout(id, true).await.unwrap();
+        assert_eq!(mock.revoked().len(), 1);
+        assert!(mon.read_characters(Some(vec![id])).unwrap().is_empty());
+        assert!(mon.characters.get(id).is_none());
+
+        mon.logout(2132411, true).await.unwrap();
+        assert_eq!(mock.revoked(), vec!["stale-refresh", "alt-refresh"]);
+        assert_eq!(mon.active_character, None);
+        assert!(mon.characters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn token_is_validated_offline() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_jwt.db");
+        mock.attach(&mut mon);
+        let id = common::CHARACTER_ID;
+
+        let token = mock.token(id, common::OWNER, "jti", 20);
+        let claims = mon.sso.validate_token(&token, Some(id)).await.unwrap();
+        assert_eq!(claims.character_id, id);
+        assert_eq!(claims.owner, common::OWNER);
+        assert_eq!(claims.scopes, vec![Scope::ReadLocation]);
+        // the keys are downloaded once
+        mon.sso.validate_token(&token, None).await.unwrap();
+        assert_eq!(mock.jwks_requests(), 1);
+
+        let foreign = mock.token(2132411, common::OWNER, "jti", 20);
+        let result = mon.sso.validate_token(&foreign, Some(id)).await;
+        assert!(matches!(result, Err(webb::Error::InvalidToken(_))));
+        let expired = mock.token(id, common::OWNER, "jti", -5);
+        assert!(mon.sso.validate_token(&expired, Some(id)).await.is_err());
+
+        // swapping the payload breaks the signature
+        let parts: Vec<&str> = token.split('.').collect();
+        let other: Vec<&str> = foreign.split('.').collect();
+        let tampered = [parts[0], other[1], parts[2]].join(".");
+        assert!(mon.sso.validate_token(&tampered, None).await.is_err());
+
+        // tokens issued by someone else are refused
+        mon.sso.base_url = String::from("https://login.eveonline.com/");
+        assert!(mon.sso.validate_token(&token, Some(id)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stored_token_claims() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_claims.db");
+        let id = expired_character(&mut mon, &mock);
+        assert!(mon.token_claims(id).await.is_err());
+        assert!(matches!(
+            mon.token_claims(2132411).await,
+            Err(webb::Error::NotAuthenticated(2132411))
+        ));
+
+        mon.ensure_token(id).await.unwrap();
+        let claims = mon.token_claims(id).await.unwrap();
+        assert_eq!(claims.owner, common::OWNER);
+        assert!(claims.expiration > chrono::Utc::now());
+    }
+
+    #[test]
+    fn scope_names_round_trip() {
+        assert_eq!(Scope::ReadLocation.as_str(), "esi-location.read_location.v1");
+        assert_eq!(Scope::from("esi-skills.read_skills.v1"), Scope::ReadSkills);
+        let unknown = Scope::from("esi-planets.manage_planets.v1");
+        assert_eq!(unknown, Scope::Other("esi-planets.manage_planets.v1".to_string()));
+        let scopes = Scope::split("publicData  esi-planets.manage_planets.v1");
+        assert_eq!(scopes, vec![Scope::PublicData, unknown]);
+        assert_eq!(Scope::join(&scopes), "publicData esi-planets.manage_planets.v1");
+    }
+
+    #[tokio::test]
+    async fn granted_scopes_are_stored() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_scopes.db");
+        let id = expired_character(&mut mon, &mock);
+        let required = [Scope::ReadLocation, Scope::ReadSkills];
+        assert_eq!(mon.missing_scopes(id, &required), required.to_vec());
+
+        mon.ensure_token(id).await.unwrap();
+        assert_eq!(mon.missing_scopes(id, &required), vec![Scope::ReadSkills]);
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert_eq!(chars[0].auth.as_ref().unwrap().scopes, vec![Scope::ReadLocation]);
+    }
+
+    #[tokio::test]
+    async fn upgrade_url_asks_for_the_missing_scopes() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_upgrade.db");
+        let id = expired_character(&mut mon, &mock);
+        mon.ensure_token(id).await.unwrap();
+
+        let auth_info = mon.get_upgrade_url(id, &[Scope::ReadSkills]).unwrap();
+        let query = auth_info.authorization_url.split_once('?').unwrap().1;
+        let scope = form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "scope")
+            .map(|(_, value)| value.into_owned());
+        assert_eq!(
+            scope.as_deref(),
+            Some("esi-location.read_location.v1 esi-skills.read_skills.v1")
+        );
+        assert!(query.contains(&auth_info.state));
+        assert_eq!(mon.pending_logins.character(&auth_info.state), Some(id));
+
+        // the SSO logs in whoever the user picks, it must be the character upgraded
+        let auth_info = mon.get_upgrade_url(2132411, &[Scope::ReadSkills]).unwrap();
+        let state = auth_info.state.clone();
+        let result = mon.auth_user(auth_info, (String::from("code"), state)).await;
+        assert!(matches!(
+            result,
+            Err(webb::Error::UnexpectedCharacter { expected: 2132411, found: 95103254 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn callback_pages_default_to_the_bundled_templates() {
+        let states = PendingStates::new();
+        states.insert("xyz");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4513));
+        let server = tokio::spawn(webb::esi::EsiManager::launch_auth_server_on(
+            addr,
+            Duration::from_secs(5),
+            Some(states),
+        ));
+        let (status, page) = fetch_callback("http://127.0.0.1:4513/login?code=abc&state=forged").await;
+        assert_eq!(status, 400);
+        assert!(page.contains("<h1>Telescope</h1>"));
+        assert!(page.contains("not valid or was already used"));
+        let (status, page) = fetch_callback("http://127.0.0.1:4513/login?code=abc&state=xyz").await;
+        assert_eq!(status, 200);
+        assert!(page.contains("Your character is logged in"));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn callback_pages_render_the_character_and_branding() {
+        let mut mon = common::new_manager("tests/databases/test_pages.db");
+        let mut zchar = Character::new();
+        zchar.id = 95103254;
+        zchar.name = "Rain <Agnon>".to_string();
+        mon.characters.insert(zchar);
+        mon.callback_pages = CallbackPages {
+            success: String::from("<p>{{character}} @ {{app_name}}</p>"),
+            failure: String::from("<p>{{reason}} @ {{app_name}}</p>"),
+            app_name: String::from("Observatory"),
+        };
+        let auth_info = mon.get_upgrade_url(95103254, &[]).unwrap();
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4514));
+        let server = mon.clone();
+        let server = tokio::spawn(async move {
+            server.serve_login_callback(addr, Duration::from_secs(5)).await
+        });
+        let (status, page) = fetch_callback("http://127.0.0.1:4514/login?code=abc").await;
+        assert_eq!(status, 422);
+        assert_eq!(page, "<p>the SSO answer is missing the code or the state @ Observatory</p>");
+        let url = format!("http://127.0.0.1:4514/login?code=abc&state={}", auth_info.state);
+        let (status, page) = fetch_callback(&url).await;
+        assert_eq!(status, 200);
+        assert_eq!(page, "<p>Rain &lt;Agnon&gt; @ Observatory</p>");
+        let (_, state) = server.await.unwrap().unwrap();
+        assert_eq!(state, auth_info.state);
+    }
+
+    #[tokio::test]
+    async fn denied_login_is_not_pending_after_a_restart() {
+        let path_str = "tests/databases/test_denied_login.db";
+        let mon = common::new_manager(path_str);
+        let auth_info = mon.get_authorize_url().unwrap();
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4517));
+        let server = mon.clone();
+        let server = tokio::spawn(async move {
+            server.serve_login_callback(addr, Duration::from_secs(5)).await
+        });
+        let url = format!("http://127.0.0.1:4517/login?error=access_denied&state={}", auth_info.state);
+        assert_eq!(send_callback(&url).await, 200);
+        let result = server.await.unwrap();
+        assert!(matches!(result, Err(webb::Error::Sso { ref error, .. }) if error == "access_denied"));
+
+        let reopened = common::open_manager(path_str).unwrap();
+        assert!(!reopened.pending_logins.contains(&auth_info.state));
+    }
+
+    #[test]
+    fn auth_service_debug_leaves_tokens_out() {
+        let mon = common::new_manager("tests/databases/test_service_debug.db");
+        let mut zchar = Character::new();
+        zchar.id = 95103254;
+        zchar.auth = Some(auth_data("secret-access", 20));
+        mon.characters.insert(zchar);
+        let (tx, _rx) = tokio::sync::mpsc::channel(1);
+        let mut service = AuthService2::new(tx, None, CallbackPages::default());
+        service.characters = Some(mon.characters.clone());
+
+        let printed = format!("{:?}", service);
+        assert!(printed.contains("95103254"));
+        assert!(!printed.contains("secret-access"));
+    }
+
+    #[test]
+    fn callback_params_are_decoded() {
+        let params = CallbackParams::parse("code=a%2Fb%3D&state=x+y&extra").unwrap();
+        assert_eq!(params.code.as_deref(), Some("a/b="));
+        assert_eq!(params.state.as_deref(), Some("x y"));
+        // a parameter without value is as good as missing
+        let params = CallbackParams::parse("code&state=xyz").unwrap();
+        assert_eq!(params.code, None);
+        assert!(matches!(params.into_result(), Err(webb::Error::InvalidCallback(_))));
+        let result = CallbackParams::parse("code=a&state=xyz&code=b");
+        assert!(matches!(result, Err(webb::Error::InvalidCallback(_))));
+    }
+
+    #[tokio::test]
+    async fn auth_server_survives_malformed_callbacks() {
+        let states = PendingStates::new();
+        states.insert("x y");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4515));
+        let server = tokio::spawn(webb::esi::EsiManager::launch_auth_server_on(
+            addr,
+            Duration::from_secs(5),
+            Some(states),
+        ));
+        assert_eq!(send_callback("http://127.0.0.1:4515/login?code&state").await, 422);
+        assert_eq!(send_callback("http://127.0.0.1:4515/login?=&&=x").await, 422);
+        let status = send_callback("http://127.0.0.1:4515/login?code=a&code=b&state=x%20y").await;
+        assert_eq!(status, 400);
+        let status = send_callback("http://127.0.0.1:4515/login?code=a%2Fb&state=x%20y").await;
+        assert_eq!(status, 200);
+        let (code, state) = server.await.unwrap().unwrap();
+        assert_eq!((code.as_str(), state.as_str()), ("a/b", "x y"));
+    }
+
+    #[tokio::test]
+    async fn auth_server_forwards_denied_consent() {
+        let states = PendingStates::new();
+        states.insert("xyz");
+        let addr = SocketAddr::from(([127, 0, 0, 1], 4516));
+        let server = tokio::spawn(webb::esi::EsiManager::launch_auth_server_on(
+            addr,
+            Duration::from_secs(5),
+            Some(states.clone()),
+        ));
+        // someone else can't cancel the login
+        let status = send_callback("http://127.0.0.1:4516/login?error=access_denied").await;
+        assert_eq!(status, 400);
+        let url = "http://127.0.0.1:4516/login?error=access_denied&error_description=User%20denied&state=xyz";
+        let (status, page) = fetch_callback(url).await;
+        assert_eq!(status, 200);
+        assert!(page.contains("User denied"));
+        match server.await.unwrap() {
+            Err(webb::Error::Sso { error, description }) => {
+                assert_eq!(error, "access_denied");
+                assert_eq!(description.as_deref(), Some("User denied"));
+            }
+            _ => panic!("the SSO error must reach the caller"),
+        }
+        assert!(!states.contains("xyz"));
+    }
+
+    #[tokio::test]
+    async fn login_survives_a_restart() {
+        let mock = MockEve::start().await;
+        let path_str = "tests/databases/test_login_session.db";
+        let mon = common::new_manager(path_str);
+        let auth_info = mon.get_authorize_url().unwrap();
+        drop(mon);
+
+        let mut mon = webb::esi::EsiManager::new(
+            common::USER_AGENT,
+            common::CLIENT_ID,
+            common::SECRET_KEY,
+            common::CALLBACK,
+            vec![],
+            path_str.to_string(),
+        )
+        .unwrap();
+        mock.attach(&mut mon);
+        assert!(mon.pending_logins.contains(&auth_info.state));
+        let player = mon
+            .complete_login((String::from("code"), auth_info.state.clone()))
+            .await
+            .unwrap()
+            .into_character();
+        assert_eq!(player.id, common::CHARACTER_ID);
+        assert_eq!(player.corp.as_ref().unwrap().name, "Alfa Corp");
+        assert_eq!(player.alliance.as_ref().unwrap().name, "Beta alliance");
+        assert_eq!(player.location, common::SOLAR_SYSTEM);
+        assert!(mon.characters.get(player.id).is_some());
+
+        // a session is only good once
+        let result = mon.complete_login((String::from("code"), auth_info.state)).await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+        assert!(mon.pending_logins.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pasted_redirect_completes_the_login() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_pasted_login.db");
+        mock.attach(&mut mon);
+
+        // a state that was never issued is refused before reaching the SSO
+        let result = mon
+            .complete_pasted_login("http://localhost:4500/login?code=abc&state=unknown")
+            .await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+        let result = mon.complete_pasted_login("http://localhost:4500/login?code=abc").await;
+        assert!(matches!(result, Err(webb::Error::InvalidCallback(_))));
+        assert_eq!(mock.token_requests(), 0);
+
+        // denied consent ends the login
+        let auth_info = mon.get_authorize_url().unwrap();
+        let redirect = format!(
+            "{}?error=access_denied&error_description=The+user+denied&state={}",
+            common::CALLBACK,
+            auth_info.state
+        );
+        let result = mon.complete_pasted_login(&redirect).await;
+        assert!(matches!(result, Err(webb::Error::Sso { ref error, .. }) if error == "access_denied"));
+        assert!(!mon.pending_logins.contains(&auth_info.state));
+        // nor does it come back on the next start
+        let reopened = common::open_manager("tests/databases/test_pasted_login.db").unwrap();
+        assert!(!reopened.pending_logins.contains(&auth_info.state));
+
+        // the whole URL, as copied from the address bar
+        let auth_info = mon.get_authorize_url().unwrap();
+        let redirect = format!("  {}?code=abc&state={}#\n", common::CALLBACK, auth_info.state);
+        let player = mon.complete_pasted_login(&redirect).await.unwrap().into_character();
+        assert_eq!(player.id, common::CHARACTER_ID);
+        assert!(mon.characters.get(player.id).is_some());
+
+        // or only the query string
+        let auth_info = mon.get_authorize_url().unwrap();
+        let query = format!("code=abc&state={}", auth_info.state);
+        let outcome = mon.complete_pasted_login(&query).await.unwrap();
+        assert!(matches!(outcome, LoginOutcome::LoggedIn(_)));
+        assert_eq!(outcome.character().id, common::CHARACTER_ID);
+        let result = mon.complete_pasted_login(&query).await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+    }
+
+    #[tokio::test]
+    async fn refresher_renews_tokens_before_they_expire() {
+        let mock = MockEve::start().await;
+        let path_str = "tests/databases/test_refresher.db";
+        let mut mon = common::new_manager(path_str);
+        let id = expired_character(&mut mon, &mock);
+        let mut zchar = mon.characters.get(id).unwrap();
+        zchar.auth = Some(auth_data("current", 10));
+        mon.write_character(&zchar).unwrap();
+        mon.characters.insert(zchar);
+
+        let now = Arc::new(Mutex::new(chrono::Utc::now()));
+        let clock = Arc::clone(&now);
+        let options = RefreshOptions {
+            poll_interval: Duration::from_millis(10),
+            clock: Arc::new(move || *clock.lock().unwrap()),
+            ..RefreshOptions::default()
+        };
+        let mut refresher = mon.start_refresher(options);
+
+        // far from the expiration nothing happens
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mock.token_requests(), 0);
+
+        *now.lock().unwrap() += chrono::Duration::seconds(9 * 60 + 30);
+        let event = tokio::time::timeout(Duration::from_secs(5), refresher.events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            event,
+            Some(RefreshEvent::Refreshed { character_id, expiration: Some(_) }) if character_id == id
+        ));
+        assert_eq!(mock.refresh_tokens(), vec!["current-refresh"]);
+        assert_eq!(mon.characters.auth(id).unwrap().refresh_token, "refresh-1");
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert_eq!(chars[0].auth.as_ref().unwrap().refresh_token, "refresh-1");
+
+        // failures are reported and tried again later, not in a loop
+        mock.set_token_error(Some("temporarily_unavailable"));
+        *now.lock().unwrap() += chrono::Duration::minutes(30);
+        let event = tokio::time::timeout(Duration::from_secs(5), refresher.events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            event,
+            Some(RefreshEvent::Failed { character_id, .. }) if character_id == id
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mock.token_requests(), 2);
+
+        // a revoked token is not tried again at all
+        mock.set_token_error(Some("invalid_grant"));
+        *now.lock().unwrap() += chrono::Duration::minutes(2);
+        let event = tokio::time::timeout(Duration::from_secs(5), refresher.events.recv())
+            .await
+            .unwrap();
+        assert!(matches!(
+            event,
+            Some(RefreshEvent::ReauthRequired { character_id }) if character_id == id
+        ));
+        assert_eq!(mon.characters_needing_reauth(), vec![id]);
+        *now.lock().unwrap() += chrono::Duration::minutes(30);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(mock.token_requests(), 3);
+
+        refresher.stop().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn owner_change_flags_the_character_as_transferred() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_transfer.db");
+        let id = expired_character(&mut mon, &mock);
+
+        // the first token seen tells who owns the character
+        mon.refresh_token(id).await.unwrap();
+        assert_eq!(mon.characters.get(id).unwrap().owner.as_deref(), Some(common::OWNER));
+
+        // a login from the account that bought it
+        mock.set_owner("bUyErAcCoUnThAsH000000000=");
+        let auth_info = mon.get_authorize_url().unwrap();
+        let outcome = mon
+            .complete_login((String::from("code"), auth_info.state))
+            .await
+            .unwrap();
+        let LoginOutcome::Transferred { character, previous_owner } = outcome else {
+            panic!("the transfer was not reported");
+        };
+        assert_eq!(previous_owner, common::OWNER);
+        assert!(character.transferred);
+        assert_eq!(character.owner.as_deref(), Some("bUyErAcCoUnThAsH000000000="));
+        // logging in again from the new account is a plain login
+        let auth_info = mon.get_authorize_url().unwrap();
+        let outcome = mon
+            .complete_login((String::from("code"), auth_info.state))
+            .await
+            .unwrap();
+        assert!(matches!(outcome, LoginOutcome::LoggedIn(ref character) if character.transferred));
+        mon.acknowledge_transfer(id).await.unwrap();
+        assert!(!mon.characters.get(id).unwrap().transferred);
+        mon.refresh_token(id).await.unwrap();
+
+        // a refresh for another account drops the tokens
+        mock.set_owner(common::OWNER);
+        let result = mon.refresh_token(id).await;
+        assert!(matches!(result, Err(webb::Error::CharacterTransferred(found)) if found == id));
+        let zchar = mon.characters.get(id).unwrap();
+        assert!(zchar.transferred);
+        assert!(zchar.auth.is_none());
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert!(chars[0].transferred);
+        assert!(chars[0].auth.is_none());
+        assert_eq!(chars[0].owner.as_deref(), Some(common::OWNER));
+        let result = mon.get_location(id).await;
+        assert!(matches!(result, Err(webb::Error::NotAuthenticated(_))));
+    }
+
+    #[tokio::test]
+    async fn revoked_token_requires_a_new_login() {
+        let mock = MockEve::start().await;
+        let path_str = "tests/databases/test_reauth.db";
+        let mut mon = common::new_manager(path_str);
+        let id = expired_character(&mut mon, &mock);
+
+        // a network blip or an SSO hiccup is worth retrying
+        mock.set_token_error(Some("server_error"));
+        let result = mon.refresh_token(id).await;
+        assert!(matches!(result, Err(ref t_error) if !t_error.needs_reauth()));
+        assert!(mon.characters_needing_reauth().is_empty());
+
+        mock.set_token_error(Some("invalid_grant"));
+        let result = mon.get_location(id).await;
+        assert!(matches!(result, Err(webb::Error::ReauthRequired(found)) if found == id));
+        assert_eq!(mon.characters_needing_reauth(), vec![id]);
+        let chars = mon.read_characters(Some(vec![id])).unwrap();
+        assert!(chars[0].needs_reauth);
+        assert!(chars[0].auth.is_none());
+        // the SSO is not asked again
+        let result = mon.refresh_token(id).await;
+        assert!(matches!(result, Err(webb::Error::ReauthRequired(_))));
+        assert_eq!(mock.token_requests(), 2);
+
+        // logging in again clears it
+        mock.set_token_error(None);
+        let auth_info = mon.get_authorize_url().unwrap();
+        let player = mon
+            .complete_login((String::from("code"), auth_info.state))
+            .await
+            .unwrap()
+            .into_character();
+        assert!(!player.needs_reauth);
+        assert!(mon.characters_needing_reauth().is_empty());
+        assert_eq!(mon.get_location(id).await.unwrap(), common::SOLAR_SYSTEM);
+    }
+
+    #[cfg(feature = "native-auth-flow")]
+    #[tokio::test]
+    async fn deep_link_completes_the_login() {
+        let mock = MockEve::start().await;
+        let mut mon = common::new_manager("tests/databases/test_deep_link.db");
+        mock.attach(&mut mon);
+        mon.callback_url = String::from("telescope://callback");
+        let auth_info = mon.get_authorize_url().unwrap();
+
+        // other links of the application are not logins
+        let link = format!("telescope://settings?code=abc&state={}", auth_info.state);
+        let result = mon.complete_deep_link(&link).await;
+        assert!(matches!(result, Err(webb::Error::InvalidCallback(_))));
+        let result = mon.complete_deep_link("telescope://callback?code=abc&state=forged").await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+        assert_eq!(mock.token_requests(), 0);
+
+        let link = format!("TELESCOPE://callback/?code=abc&state={}", auth_info.state);
+        let player = mon.complete_deep_link(&link).await.unwrap().into_character();
+        assert_eq!(player.id, common::CHARACTER_ID);
+        let result = mon.complete_deep_link(&link).await;
+        assert!(matches!(result, Err(webb::Error::AuthStateReplayed)));
+    }
+
+    #[tokio::test]
+    async fn configured_urls_reach_a_local_server() {
+        let mock = MockEve::start().await;
+        let path_str = "tests/databases/test_configured_urls.db";
+        common::remove_database(path_str);
+        let mut mon = mock
+            .builder(path_str)
+            .scopes(vec![Scope::ReadLocation])
+            .datasource(Datasource::Singularity)
+            .build()
+            .unwrap();
+
+        let auth_info = mon.get_authorize_url().unwrap();
+        assert!(auth_info
+            .authorization_url
+            .starts_with(&format!("{}v2/oauth/authorize?", mock.url)));
+        let state = auth_info.state.clone();
+        let player = mon
+            .auth_user(auth_info, (String::from("code"), state))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(player.id, common::CHARACTER_ID);
+        assert_eq!(mon.get_location(player.id).await.unwrap(), common::SOLAR_SYSTEM);
+
+        mon.refresh_token(player.id).await.unwrap();
+        assert_eq!(mock.token_requests(), 2);
+
+        // every ESI call asks for the configured server
+        let datasources = mock.datasources();
+        assert!(!datasources.is_empty());
+        assert!(datasources.iter().all(|datasource| datasource == "singularity"));
+    }
 }
@@ -0,0 +1,313 @@
+mod common;
+
+#[cfg(test)]
+mod player_database {
+    use crate::common::{open_manager, remove_database};
+    use rusqlite::Connection;
+    use webb::esi::player_database::SCHEMA_VERSION;
+
+    #[cfg(feature = "crypted-db")]
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[cfg(feature = "crypted-db")]
+    use std::path::Path;
+    #[cfg(feature = "crypted-db")]
+    use std::sync::Arc;
+    #[cfg(not(feature = "crypted-db"))]
+    use std::fs;
+    #[cfg(feature = "crypted-db")]
+    use uuid::Uuid;
+    #[cfg(feature = "crypted-db")]
+    use crate::common::open_manager_with_key;
+    #[cfg(feature = "crypted-db")]
+    use webb::esi::{DatabaseKey, EsiManager};
+
+    // opens the database the way the library does
+    fn raw_connection(path_str: &str) -> Connection {
+        let connection = Connection::open(path_str).unwrap();
+        #[cfg(feature = "crypted-db")]
+        {
+            let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, "telescope".as_bytes());
+            let query = ["PRAGMA key = '", uuid.to_string().as_str(), "'"].concat();
+            connection.execute_batch(&query).unwrap();
+        }
+        connection
+    }
+
+    // copies a plain text fixture, encrypting it when the library expects it
+    fn copy_fixture(fixture: &str, path_str: &str) {
+        remove_database(path_str);
+        #[cfg(feature = "crypted-db")]
+        {
+            let uuid = Uuid::new_v5(&Uuid::NAMESPACE_OID, "telescope".as_bytes());
+            let connection = Connection::open(fixture).unwrap();
+            let query = format!(
+                "ATTACH DATABASE '{}' AS encrypted KEY '{}'; SELECT sqlcipher_export('encrypted'); DETACH DATABASE encrypted;",
+                path_str, uuid
+            );
+            connection.execute_batch(&query).unwrap();
+        }
+        #[cfg(not(feature = "crypted-db"))]
+        {
+            fs::copy(fixture, path_str).unwrap();
+        }
+    }
+
+    fn schema_version(path_str: &str) -> u32 {
+        let connection = raw_connection(path_str);
+        let query = "SELECT value FROM metadata WHERE id = 'db'";
+        let version: String = connection.query_row(query, [], |row| row.get(0)).unwrap();
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn new_database_has_current_version() {
+        let path_str = "tests/databases/test_migrate_new.db";
+        remove_database(path_str);
+        let _ = open_manager(path_str).unwrap();
+        assert_eq!(schema_version(path_str), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn migrates_char_auth_fixture() {
+        let path_str = "tests/databases/test_migrate_char0.db";
+        copy_fixture("tests/databases/char0.db", path_str);
+
+        let esimon = open_manager(path_str).unwrap();
+        assert_eq!(esimon.characters.len(), 1);
+        let chars = esimon.characters.list();
+        assert_eq!(chars[0].name, "Rain Agnon");
+        assert_eq!(chars[0].corp.as_ref().unwrap().name, "Alfa Corp");
+        assert!(chars[0].auth.is_none());
+        assert_eq!(schema_version(path_str), SCHEMA_VERSION);
+
+        let connection = raw_connection(path_str);
+        let query = "SELECT count(*) FROM sqlite_master WHERE name = 'char_auth'";
+        let tables: i32 = connection.query_row(query, [], |row| row.get(0)).unwrap();
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    fn migrates_metadata_token() {
+        let path_str = "tests/databases/test_migrate_metadata.db";
+        copy_fixture("tests/databases/char0.db", path_str);
+        {
+            // the layout of the first releases kept a single token in metadata
+            let connection = raw_connection(path_str);
+            let mut query = String::from("DROP TABLE char_auth;");
+            query += "INSERT INTO metadata (id,value) VALUES ('token','access');";
+            query += "INSERT INTO metadata (id,value) VALUES ('refresh_token','refresh');";
+            query += "INSERT INTO metadata (id,value) VALUES ('expiration','2024-07-08T10:00:00+00:00');";
+            connection.execute_batch(&query).unwrap();
+        }
+
+        let esimon = open_manager(path_str).unwrap();
+        let auth = esimon.characters.list()[0].auth.clone().unwrap();
+        assert_eq!(auth.token, "access");
+        assert_eq!(auth.refresh_token, "refresh");
+        assert!(auth.expiration.is_some());
+        // the legacy token doesn't say which scopes it carries
+        assert!(auth.scopes.is_empty());
+
+        let connection = raw_connection(path_str);
+        let query = "SELECT count(*) FROM metadata WHERE id <> 'db'";
+        let rows: i32 = connection.query_row(query, [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 0);
+    }
+
+    #[test]
+    fn concurrent_opens_migrate_once() {
+        let path_str = "tests/databases/test_migrate_concurrent.db";
+        copy_fixture("tests/databases/char0.db", path_str);
+        // leaving the rollback journal needs an exclusive lock, do it up front so only
+        // the migrations race
+        let _mode: String = raw_connection(path_str)
+            .query_row("PRAGMA journal_mode=WAL", [], |row| row.get(0))
+            .unwrap();
+
+        let opens: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || open_manager(path_str).map(|esimon| esimon.characters.len()))
+            })
+            .collect();
+        for open in opens {
+            assert_eq!(open.join().unwrap().unwrap(), 1);
+        }
+        assert_eq!(schema_version(path_str), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let path_str = "tests/databases/test_migrate_newer.db";
+        remove_database(path_str);
+        let _ = open_manager(path_str).unwrap();
+        {
+            let connection = raw_connection(path_str);
+            let query = "UPDATE metadata SET value = '99' WHERE id = 'db'";
+            connection.execute(query, []).unwrap();
+        }
+
+        match open_manager(path_str) {
+            Err(webb::Error::SchemaVersion { found, supported }) => {
+                assert_eq!(found, 99);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            _ => panic!("a newer database must be refused"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "crypted-db")]
+    fn custom_key_is_required_to_open() {
+        let path_str = "tests/databases/test_key.db";
+        remove_database(path_str);
+        let key = DatabaseKey::Passphrase(String::from("it's a secret"));
+        let _ = open_manager_with_key(path_str, key.clone()).unwrap();
+
+        assert!(open_manager(path_str).is_err());
+        let wrong = DatabaseKey::Passphrase(String::from("not the secret"));
+        assert!(open_manager_with_key(path_str, wrong).is_err());
+        assert!(open_manager_with_key(path_str, key).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "crypted-db")]
+    fn key_provider_is_asked_for_the_passphrase() {
+        let path_str = "tests/databases/test_key_provider.db";
+        remove_database(path_str);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let key = DatabaseKey::Provider(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(String::from("from the keyring"))
+        }));
+        let _ = open_manager_with_key(path_str, key).unwrap();
+        assert!(calls.load(Ordering::SeqCst) > 0);
+
+        let key = DatabaseKey::Passphrase(String::from("from the keyring"));
+        assert!(open_manager_with_key(path_str, key).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "crypted-db")]
+    fn rekey_changes_the_key() {
+        let path_str = "tests/databases/test_rekey.db";
+        remove_database(path_str);
+        let old_key = DatabaseKey::Passphrase(String::from("old"));
+        let new_key = DatabaseKey::Passphrase(String::from("new"));
+        let mut esimon = open_manager_with_key(path_str, old_key.clone()).unwrap();
+        let clone = esimon.clone();
+        esimon.rekey(new_key.clone()).unwrap();
+        // clones made before switch to the new key too
+        assert!(clone.read_characters(None).is_ok());
+        // the manager keeps working with the new key
+        assert!(esimon.read_characters(None).is_ok());
+
+        assert!(open_manager_with_key(path_str, old_key).is_err());
+        assert!(open_manager_with_key(path_str, new_key).is_ok());
+    }
+
+    #[test]
+    #[cfg(feature = "crypted-db")]
+    fn converts_legacy_key() {
+        let path_str = "tests/databases/test_legacy_key.db";
+        copy_fixture("tests/databases/char0.db", path_str);
+        let key = DatabaseKey::Passphrase(String::from("mine"));
+
+        assert!(EsiManager::migrate_legacy_key(path_str, &key).unwrap());
+        assert!(!EsiManager::migrate_legacy_key(path_str, &key).unwrap());
+        assert!(open_manager(path_str).is_err());
+        let esimon = open_manager_with_key(path_str, key.clone()).unwrap();
+        assert_eq!(esimon.characters.len(), 1);
+
+        // a wrong path is not silently created
+        let missing = "tests/databases/test_legacy_key_missing.db";
+        remove_database(missing);
+        assert!(EsiManager::migrate_legacy_key(missing, &key).is_err());
+        assert!(!Path::new(missing).exists());
+    }
+
+    #[test]
+    fn database_uses_wal() {
+        let path_str = "tests/databases/test_wal.db";
+        remove_database(path_str);
+        let _ = open_manager(path_str).unwrap();
+        let connection = raw_connection(path_str);
+        let mode: String = connection
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+
+    #[test]
+    fn failed_delete_is_reported() {
+        let path_str = "tests/databases/test_failed_delete.db";
+        remove_database(path_str);
+        let mut esimon = open_manager(path_str).unwrap();
+        let mut corp = webb::objects::Corporation::new();
+        corp.id = 98660791;
+        esimon.write_corporation(&corp).unwrap();
+        raw_connection(path_str)
+            .execute_batch(
+                "CREATE TRIGGER keep_corp BEFORE DELETE ON corp BEGIN SELECT RAISE(ABORT, 'kept'); END;",
+            )
+            .unwrap();
+
+        let result = esimon.remove_corporation(Some(vec![98660791]));
+        assert!(matches!(result, Err(webb::Error::Database(_))));
+        assert_eq!(esimon.read_corporation(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    #[cfg(feature = "crypted-db")]
+    fn connections_are_reused() {
+        let path_str = "tests/databases/test_pool.db";
+        remove_database(path_str);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&calls);
+        let key = DatabaseKey::Provider(Arc::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Ok(String::from("pooled"))
+        }));
+        let mut esimon = open_manager_with_key(path_str, key).unwrap();
+        let opened = calls.load(Ordering::SeqCst);
+
+        let mut corp = webb::objects::Corporation::new();
+        for id in 1..20 {
+            corp.id = id;
+            corp.name = format!("corporation {}", id);
+            esimon.write_corporation(&corp).unwrap();
+            esimon.read_corporation(Some(vec![id])).unwrap();
+        }
+        // every call above went through the connection already open
+        assert_eq!(calls.load(Ordering::SeqCst), opened);
+    }
+
+    #[tokio::test]
+    async fn login_sessions_expire() {
+        let path_str = "tests/databases/test_login_expiry.db";
+        remove_database(path_str);
+        let mut esimon = open_manager(path_str).unwrap();
+        let fresh = esimon.get_authorize_url().unwrap();
+        let stale = esimon.get_authorize_url().unwrap();
+        let forgotten = esimon.get_authorize_url().unwrap();
+        {
+            let connection = raw_connection(path_str);
+            let query = "UPDATE login_session SET created_at = '2024-07-08T10:00:00+00:00' WHERE state IN (?1,?2)";
+            connection.execute(query, [&stale.state, &forgotten.state]).unwrap();
+        }
+
+        let result = esimon
+            .complete_login((String::from("code"), stale.state.clone()))
+            .await;
+        assert!(matches!(result, Err(webb::Error::AuthStateExpired)));
+
+        // expired sessions are dropped when the database is opened
+        let esimon = open_manager(path_str).unwrap();
+        assert!(esimon.pending_logins.contains(&fresh.state));
+        assert!(!esimon.pending_logins.contains(&forgotten.state));
+        let connection = raw_connection(path_str);
+        let query = "SELECT count(*) FROM login_session";
+        let rows: i32 = connection.query_row(query, [], |row| row.get(0)).unwrap();
+        assert_eq!(rows, 1);
+    }
+}